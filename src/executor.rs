@@ -0,0 +1,241 @@
+// Runs the plays of a playbook, task by task, against their hosts.
+//
+// There is no inventory yet, so only the implicit localhost can be targeted
+use std::collections::HashMap;
+
+use crate::modules::{ModuleError, Registry, Result, TaskResult};
+use crate::playbook::{Play, Playbook};
+
+const LOCALHOST: &[&str] = &["localhost", "127.0.0.1", "::1"];
+
+fn banner(title: String) {
+    let width = 80usize.saturating_sub(title.len() + 1).max(3);
+    println!("\n{title} {}", "*".repeat(width));
+}
+
+#[derive(Default)]
+pub struct Executor {
+    registry: Registry,
+    check_mode: bool,
+    // host -> registered variable -> result
+    registered: HashMap<String, HashMap<String, TaskResult>>,
+}
+
+impl Executor {
+    /// An executor which can run the builtin modules
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_registry(registry: Registry) -> Self {
+        Self {
+            registry,
+            ..Default::default()
+        }
+    }
+
+    /// Only report what would change, without changing anything
+    pub fn check_mode(mut self, check_mode: bool) -> Self {
+        self.check_mode = check_mode;
+        self
+    }
+
+    /// The result a task stored on `host` with `register: name`
+    pub fn registered(&self, host: &str, name: &str) -> Option<&TaskResult> {
+        self.registered.get(host)?.get(name)
+    }
+
+    pub fn run(&mut self, playbook: &Playbook) -> Result<()> {
+        for play in &playbook.plays {
+            self.run_play(play)?;
+        }
+        Ok(())
+    }
+
+    fn select_hosts<'a>(&self, play: &'a Play) -> Vec<&'a str> {
+        let mut hosts = Vec::new();
+        for host in &play.hosts {
+            if LOCALHOST.contains(&host.as_str()) {
+                hosts.push(host.as_str());
+            } else {
+                println!(
+                    "[WARNING]: Could not match supplied host pattern, \
+                     ignoring: {host}"
+                );
+            }
+        }
+        hosts
+    }
+
+    fn run_play(&mut self, play: &Play) -> Result<()> {
+        banner(format!("PLAY [{}]", play.name));
+
+        // Resolve every module and parse its arguments up front so a typo in
+        // the last task doesn't leave the host half-configured
+        for task in &play.tasks {
+            if let Err(e) = self.registry.prepare(&task.module, &task.args) {
+                return Err(ModuleError::PlainMessage(format!(
+                    "{e} (at {})",
+                    task.mark
+                )));
+            }
+        }
+
+        let hosts = self.select_hosts(play);
+        if hosts.is_empty() {
+            println!("skipping: no hosts matched");
+            return Ok(());
+        }
+
+        for task in &play.tasks {
+            banner(format!("TASK [{}]", task.display_name()));
+            for host in &hosts {
+                let action = self.registry.prepare(&task.module, &task.args)?;
+                let result = match action(self.check_mode) {
+                    Ok(result) => result,
+                    Err(e) => TaskResult::failed(e.to_string()),
+                };
+
+                if let Some(name) = &task.register {
+                    self.registered
+                        .entry(host.to_string())
+                        .or_default()
+                        .insert(name.clone(), result.clone());
+                }
+
+                if result.failed {
+                    let msg = if result.msg.is_empty() {
+                        result.stderr.trim()
+                    } else {
+                        result.msg.as_str()
+                    };
+                    println!("fatal: [{host}]: FAILED! => {msg}");
+                    // The message was printed above, the error only says
+                    // where the playbook stopped
+                    return Err(ModuleError::PlainMessage(format!(
+                        "Task '{}' (at {}) failed on {host}",
+                        task.display_name(),
+                        task.mark
+                    )));
+                } else if result.skipped {
+                    println!("skipping: [{host}]");
+                } else if result.changed {
+                    println!("changed: [{host}]");
+                } else {
+                    println!("ok: [{host}]");
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::Module;
+    use crate::playbook::PlayParser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+    use yaml_rust::Yaml;
+
+    // Records the `id` of every task it runs, failing when asked to
+    #[derive(Clone, Default)]
+    struct Stub(Rc<RefCell<Vec<String>>>);
+
+    impl Module for Stub {
+        type Args = Yaml;
+
+        fn parse_args(&self, args: &Yaml) -> Result<Self::Args> {
+            match args["id"].as_str() {
+                Some(_) => Ok(args.clone()),
+                None => Err(ModuleError::PlainMessage("no id".to_string())),
+            }
+        }
+
+        fn run(&self, args: Self::Args) -> Result<TaskResult> {
+            let id = args["id"].as_str().unwrap_or_default().to_string();
+            self.0.borrow_mut().push(id.clone());
+            match args["fail"].as_bool() {
+                Some(true) => Ok(TaskResult::failed(format!("{id} failed"))),
+                _ => Ok(TaskResult::new(true).with_msg(id)),
+            }
+        }
+    }
+
+    fn executor(stub: &Stub) -> Executor {
+        let mut registry = Registry::empty();
+        registry.register("acme.tools.stub", stub.clone());
+        Executor::with_registry(registry)
+    }
+
+    fn playbook(tasks: &str) -> Playbook {
+        let source = format!("- hosts: localhost\n  tasks:{tasks}");
+        PlayParser::new().parse(&source).unwrap()
+    }
+
+    #[test]
+    fn executor_order() {
+        let stub = Stub::default();
+        let mut executor = executor(&stub);
+        let playbook = playbook(
+            r#"
+  - stub: {id: first}
+  - acme.tools.stub: {id: second}
+    register: second
+"#,
+        );
+        executor.run(&playbook).unwrap();
+        assert_eq!(*stub.0.borrow(), ["first", "second"]);
+
+        let registered = executor.registered("localhost", "second").unwrap();
+        assert!(registered.changed);
+        assert_eq!(registered.msg, "second");
+        assert!(executor.registered("localhost", "first").is_none());
+    }
+
+    #[test]
+    fn executor_failure() {
+        let stub = Stub::default();
+        let mut executor = executor(&stub);
+        let playbook = playbook(
+            r#"
+  - stub: {id: first}
+  - name: Break
+    stub: {id: second, fail: true}
+    register: broken
+  - stub: {id: third}
+"#,
+        );
+        let e = executor.run(&playbook).unwrap_err().to_string();
+        assert_eq!(*stub.0.borrow(), ["first", "second"]);
+        assert_eq!(e, "Task 'Break' (at line 4, column 5) failed on localhost");
+        // Failed results are registered too
+        let broken = executor.registered("localhost", "broken").unwrap();
+        assert!(broken.failed);
+        assert_eq!(broken.msg, "second failed");
+    }
+
+    #[test]
+    fn executor_prepares_first() {
+        let stub = Stub::default();
+        let mut executor = executor(&stub);
+        let playbook = playbook(
+            r#"
+  - stub: {id: first}
+  - stub: {}
+  - nope: {}
+"#,
+        );
+        let e = executor.run(&playbook).unwrap_err().to_string();
+        assert!(e.contains("no id (at line 4, column 5)"), "{e}");
+        assert!(stub.0.borrow().is_empty());
+
+        // Plays without a known host run nothing
+        let playbook = PlayParser::new()
+            .parse("- hosts: web\n  tasks:\n  - stub: {id: first}\n")
+            .unwrap();
+        executor.run(&playbook).unwrap();
+        assert!(stub.0.borrow().is_empty());
+    }
+}
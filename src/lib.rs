@@ -1,2 +1,4 @@
+pub mod executor;
 pub mod facts;
 pub mod modules;
+pub mod playbook;
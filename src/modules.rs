@@ -1,10 +1,206 @@
 pub mod add_host;
 pub mod apt;
 pub mod archive;
+pub mod args;
 pub mod git;
+mod temp;
+pub mod unarchive;
+
+use std::collections::{BTreeMap, HashMap};
+use std::rc::Rc;
+
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
 
 pub type Result<T> = std::result::Result<T, ModuleError>;
 
+/// A unit of work that can be invoked from a task.
+///
+/// Arguments are parsed for every task before any task runs, so that a
+/// mistake late in a play is reported before the host is touched.
+pub trait Module {
+    type Args: 'static;
+
+    fn parse_args(&self, args: &Yaml) -> Result<Self::Args>;
+
+    /// Performs the work. Modules which ran but did not get the host into
+    /// the requested state may either return an `Err` or a `TaskResult`
+    /// marked as failed, the latter when there is output worth keeping.
+    fn run(&self, args: Self::Args) -> Result<TaskResult>;
+
+    /// Reports what `run` would do, without changing the host. Used in check
+    /// mode; modules which can't predict their changes are skipped.
+    fn check(&self, _args: Self::Args) -> Result<TaskResult> {
+        Ok(TaskResult::skipped(
+            "Check mode is not supported by this module",
+        ))
+    }
+}
+
+/// A task whose arguments have been parsed and is ready to run. Passing
+/// `true` runs it in check mode.
+pub type Action<'a> = Box<dyn FnOnce(bool) -> Result<TaskResult> + 'a>;
+
+/// The outcome of running a module on a host
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TaskResult {
+    pub changed: bool,
+    pub failed: bool,
+    pub skipped: bool,
+    pub msg: String,
+    pub stdout: String,
+    pub stderr: String,
+    pub rc: Option<i32>,
+    /// Module specific return values, such as `before` and `after` for git
+    pub data: BTreeMap<String, Yaml>,
+}
+
+impl TaskResult {
+    pub fn new(changed: bool) -> Self {
+        Self {
+            changed,
+            ..Default::default()
+        }
+    }
+
+    pub fn failed(msg: impl Into<String>) -> Self {
+        Self {
+            failed: true,
+            msg: msg.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn skipped(msg: impl Into<String>) -> Self {
+        Self {
+            skipped: true,
+            msg: msg.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_msg(mut self, msg: impl Into<String>) -> Self {
+        self.msg = msg.into();
+        self
+    }
+
+    /// Records the output of a command, marking the result as failed if the
+    /// command did not succeed
+    pub fn with_output(mut self, output: &std::process::Output) -> Self {
+        self.stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+        self.stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        self.rc = output.status.code();
+        self.failed |= !output.status.success();
+        self
+    }
+
+    pub fn set(&mut self, key: &str, value: Yaml) {
+        self.data.insert(key.to_string(), value);
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Yaml> {
+        self.data.get(key)
+    }
+
+    /// The result as a mapping, the way it is exposed to `register`
+    pub fn to_yaml(&self) -> Yaml {
+        let mut hash = Hash::new();
+        let mut insert = |key: &str, value: Yaml| {
+            hash.insert(Yaml::String(key.to_string()), value);
+        };
+        insert("changed", Yaml::Boolean(self.changed));
+        insert("failed", Yaml::Boolean(self.failed));
+        insert("skipped", Yaml::Boolean(self.skipped));
+        insert("msg", Yaml::String(self.msg.clone()));
+        insert("stdout", Yaml::String(self.stdout.clone()));
+        insert("stderr", Yaml::String(self.stderr.clone()));
+        insert(
+            "rc",
+            self.rc.map_or(Yaml::Null, |rc| Yaml::Integer(rc.into())),
+        );
+        for (key, value) in &self.data {
+            insert(key, value.clone());
+        }
+        Yaml::Hash(hash)
+    }
+}
+
+// Hides `Module::Args` so that different modules can share a registry
+trait AnyModule {
+    fn prepare(&self, args: &Yaml) -> Result<Action<'_>>;
+}
+
+impl<M: Module> AnyModule for M {
+    fn prepare(&self, args: &Yaml) -> Result<Action<'_>> {
+        let args = self.parse_args(args)?;
+        Ok(Box::new(move |check_mode| match check_mode {
+            true => self.check(args),
+            false => self.run(args),
+        }))
+    }
+}
+
+/// Maps module names to their implementations.
+///
+/// Every module is registered under its fully-qualified name, such as
+/// `rustible.builtin.git`, and under its short name (`git`) unless that
+/// short name already belongs to an earlier module. Builtin modules are
+/// registered first, so they keep their short names.
+pub struct Registry {
+    modules: HashMap<String, Rc<dyn AnyModule>>,
+}
+
+impl Registry {
+    /// An empty registry, without even the builtin modules
+    pub fn empty() -> Self {
+        Self {
+            modules: HashMap::new(),
+        }
+    }
+
+    /// Registers `module` under `fqcn` and its short name, replacing any
+    /// module previously registered under the same `fqcn`
+    pub fn register<M: Module + 'static>(&mut self, fqcn: &str, module: M) {
+        let module: Rc<dyn AnyModule> = Rc::new(module);
+        if let Some((_, short)) = fqcn.rsplit_once('.') {
+            self.modules
+                .entry(short.to_string())
+                .or_insert_with(|| module.clone());
+        }
+        self.modules.insert(fqcn.to_string(), module);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.modules.contains_key(name)
+    }
+
+    /// Looks up the module `name` and parses `args` for it
+    pub fn prepare(&self, name: &str, args: &Yaml) -> Result<Action<'_>> {
+        match self.modules.get(name) {
+            Some(module) => module.prepare(args),
+            None => Err(ModuleError::PlainMessage(format!(
+                "couldn't resolve module/action '{name}'"
+            ))),
+        }
+    }
+}
+
+impl Default for Registry {
+    /// A registry holding the builtin modules
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register("rustible.builtin.apt", apt::Apt);
+        registry
+            .register("rustible.builtin.apt_repository", apt::AptRepository);
+        registry
+            .register("rustible.builtin.dpkg_selections", apt::DpkgSelections);
+        registry.register("rustible.builtin.archive", archive::Archive);
+        registry.register("rustible.builtin.git", git::Git);
+        registry.register("rustible.builtin.unarchive", unarchive::Unarchive);
+        registry
+    }
+}
+
 #[derive(Debug)]
 pub enum ModuleError {
     PlainMessage(String),
@@ -34,3 +230,70 @@ impl From<std::io::Error> for ModuleError {
         ModuleError::IOError(value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo(bool);
+
+    impl Module for Echo {
+        type Args = ();
+
+        fn parse_args(&self, args: &Yaml) -> Result<Self::Args> {
+            match args {
+                Yaml::Hash(h) if h.is_empty() => Ok(()),
+                _ => Err(ModuleError::PlainMessage("no args".to_string())),
+            }
+        }
+
+        fn run(&self, _args: Self::Args) -> Result<TaskResult> {
+            Ok(TaskResult::new(self.0))
+        }
+    }
+
+    #[test]
+    fn registry_names() {
+        let mut registry = Registry::default();
+        assert!(registry.contains("git"));
+        assert!(registry.contains("rustible.builtin.git"));
+
+        registry.register("acme.tools.git", Echo(true));
+        registry.register("acme.tools.echo", Echo(true));
+        assert!(registry.contains("acme.tools.git"));
+        assert!(registry.contains("echo"));
+
+        let args = Yaml::Hash(Default::default());
+        let run = |name, check_mode| {
+            registry.prepare(name, &args).unwrap()(check_mode).unwrap()
+        };
+        assert!(run("acme.tools.git", false).changed);
+        assert!(run("echo", false).changed);
+        // Modules without check mode support are skipped
+        assert!(run("echo", true).skipped);
+        assert!(registry.prepare("echo", &Yaml::Null).is_err());
+        assert!(registry.prepare("nope", &args).is_err());
+    }
+
+    #[test]
+    fn task_result_output() {
+        let output = std::process::Command::new("sh")
+            .arg("-c")
+            .arg("echo out; echo err >&2; exit 3")
+            .output()
+            .unwrap();
+        let mut result = TaskResult::new(true).with_output(&output);
+        result.set("before", Yaml::Null);
+
+        assert!(result.changed);
+        assert!(result.failed);
+        assert_eq!(result.rc, Some(3));
+        assert_eq!(result.stdout, "out\n");
+        assert_eq!(result.stderr, "err\n");
+
+        let yaml = result.to_yaml();
+        assert_eq!(yaml["rc"], Yaml::Integer(3));
+        assert_eq!(yaml["failed"], Yaml::Boolean(true));
+        assert_eq!(yaml["before"], Yaml::Null);
+    }
+}
@@ -1,22 +1,68 @@
-use std::process::Command;
+// Installs, upgrades and removes packages with apt
+mod deb;
+mod repository;
+mod selections;
+mod simulate;
+
+use super::args::Args;
+use super::{Module, ModuleError, TaskResult};
+use simulate::{yaml_changes, Simulation};
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+pub use repository::{AptRepository, AptRepositoryArgs};
+pub use selections::{DpkgSelections, DpkgSelectionsArgs};
+
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::path::Path;
+use std::process::{Command, Output};
 use std::string::FromUtf8Error;
-use std::{error, io};
-use std::{fmt, result};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::{error, fmt, fs, io, result};
 
-enum Subcommand {
-    Update,
-}
+const OPTIONS: &[&str] = &[
+    "name",
+    "state",
+    "update_cache",
+    "cache_valid_time",
+    "allow_downgrade",
+    "deb",
+    "purge",
+    "autoremove",
+    "autoclean",
+    "install_recommends",
+    "dpkg_options",
+    "upgrade",
+    "lock_timeout",
+];
+
+const DPKG_STATUS: &str = "/var/lib/dpkg/status";
+
+// Touched by `apt-get update`, the stamp only when every list was fetched
+const CACHE_STAMPS: &[&str] = &[
+    "/var/lib/apt/periodic/update-success-stamp",
+    "/var/lib/apt/lists",
+];
 
 type Result<T> = result::Result<T, AptError>;
 
 #[derive(Debug)]
-struct AptError {
-    details: String,
+enum AptError {
+    Failed(String),
+    /// Another process kept holding a dpkg or apt lock for longer than
+    /// `lock_timeout`
+    Locked {
+        lock: String,
+        holder: Option<String>,
+    },
 }
 
 impl AptError {
     fn new(details: String) -> Self {
-        AptError { details }
+        AptError::Failed(details)
     }
 }
 
@@ -24,7 +70,17 @@ impl error::Error for AptError {}
 
 impl fmt::Display for AptError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "Error during `apt-get` operation: {}", self.details)
+        write!(f, "Error during `apt-get` operation: ")?;
+        match self {
+            Self::Failed(details) => write!(f, "{details}"),
+            Self::Locked { lock, holder } => {
+                write!(f, "Timed out waiting for the lock on {lock}")?;
+                match holder {
+                    Some(holder) => write!(f, ", held by {holder}"),
+                    None => Ok(()),
+                }
+            }
+        }
     }
 }
 
@@ -40,101 +96,1227 @@ impl From<io::Error> for AptError {
     }
 }
 
+impl From<ModuleError> for AptError {
+    fn from(err: ModuleError) -> Self {
+        AptError::new(err.to_string())
+    }
+}
+
 impl From<&str> for AptError {
     fn from(str: &str) -> Self {
         AptError::new(str.to_string())
     }
 }
 
-// impl From<Utf8Error> for AptError {
-//     fn from(err: Utf8Error) -> AptError {
-//         AptError::Parse(err)
-//     }
-// }
+// Reports a lock timeout as a failed result naming the lock and its holder,
+// so that it can be told apart from apt itself failing
+fn task_result(result: Result<TaskResult>) -> super::Result<TaskResult> {
+    let error = match result {
+        Err(error @ AptError::Locked { .. }) => error,
+        result => return Ok(result?),
+    };
+    let mut failed = TaskResult::failed(error.to_string());
+    if let AptError::Locked { lock, holder } = error {
+        failed.set("lock", Yaml::String(lock));
+        failed.set("lock_holder", holder.map_or(Yaml::Null, Yaml::String));
+    }
+    Ok(failed)
+}
+
+impl From<AptError> for ModuleError {
+    fn from(err: AptError) -> Self {
+        ModuleError::PlainMessage(err.to_string())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    /// Install packages which are missing
+    Present,
+    /// Remove packages which are installed
+    Absent,
+    /// Install missing packages and upgrade outdated ones
+    Latest,
+    /// Like `present`, but also repair broken dependencies
+    Fixed,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Upgrade {
+    /// `apt-get upgrade`, which never removes packages
+    Safe,
+    /// `apt-get dist-upgrade`, which may remove packages to resolve conflicts
+    Full,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AptArgs {
+    /// Package names, optionally pinned to a version with `pkg=1.2.3`. The
+    /// version may end in a wildcard, as in `pkg=1.2.*`
+    pub names: Vec<String>,
+    pub state: State,
+    /// Run `apt-get update` before anything else
+    pub update_cache: bool,
+    /// Only update the cache when it is older than this many seconds
+    pub cache_valid_time: Option<u64>,
+    /// Allow pinned versions older than the installed ones
+    pub allow_downgrade: bool,
+    /// Path or URL of a `.deb` file to install instead of `names`
+    pub deb: Option<String>,
+    /// Remove configuration files along with packages
+    pub purge: bool,
+    /// Remove dependencies which are no longer needed
+    pub autoremove: bool,
+    /// Delete downloaded packages which can no longer be downloaded
+    pub autoclean: bool,
+    /// Overrides apt's configuration for installing recommended packages
+    pub install_recommends: Option<bool>,
+    /// Options passed to dpkg, without the leading `--`
+    pub dpkg_options: Vec<String>,
+    /// Upgrade every package on the system
+    pub upgrade: Option<Upgrade>,
+    /// Seconds to wait for another package manager to release its lock
+    pub lock_timeout: u64,
+}
+
+impl AptArgs {
+    pub fn new(names: Vec<String>) -> Self {
+        Self {
+            names,
+            state: State::Present,
+            update_cache: false,
+            cache_valid_time: None,
+            allow_downgrade: false,
+            deb: None,
+            purge: false,
+            autoremove: false,
+            autoclean: false,
+            install_recommends: None,
+            // Keep changed configuration files without asking
+            dpkg_options: vec![
+                "force-confdef".to_string(),
+                "force-confold".to_string(),
+            ],
+            upgrade: None,
+            lock_timeout: 60,
+        }
+    }
+}
+
+// Splits `pkg=version` into the package name and the version pattern
+fn split_version(name: &str) -> (&str, Option<&str>) {
+    match name.split_once('=') {
+        Some((name, version)) => (name, Some(version)),
+        None => (name, None),
+    }
+}
+
+// Whether `version` matches a pattern where `*` stands for any characters
+fn version_matches(pattern: &str, version: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = version.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.peekable();
+    while let Some(part) = parts.next() {
+        if parts.peek().is_none() {
+            return rest.ends_with(part);
+        }
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.is_empty()
+}
+
+// Orders the characters of the non-digit parts of a version the way dpkg
+// does: `~` before the end of the string, letters before everything else
+fn char_order(c: Option<u8>) -> i32 {
+    match c {
+        None => 0,
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => c.into(),
+        Some(c) => i32::from(c) + 256,
+    }
+}
+
+// Compares upstream versions or revisions, alternating between non-digit
+// parts compared by character and digit parts compared numerically
+fn compare_part(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.as_bytes(), b.as_bytes());
+    let is_digit = |s: &[u8]| s.first().is_some_and(u8::is_ascii_digit);
+    while !a.is_empty() || !b.is_empty() {
+        while (!a.is_empty() && !is_digit(a)) || (!b.is_empty() && !is_digit(b))
+        {
+            let order = char_order(a.first().copied())
+                .cmp(&char_order(b.first().copied()));
+            if order != Ordering::Equal {
+                return order;
+            }
+            a = a.get(1..).unwrap_or_default();
+            b = b.get(1..).unwrap_or_default();
+        }
+
+        let digits =
+            |s: &[u8]| s.iter().take_while(|c| c.is_ascii_digit()).count();
+        let (a_digits, b_digits) = (digits(a), digits(b));
+        let trim = |s: &'_ [u8]| {
+            let zeros = s.iter().take_while(|c| **c == b'0').count();
+            s[zeros..].to_vec()
+        };
+        let (a_number, b_number) = (trim(&a[..a_digits]), trim(&b[..b_digits]));
+        let order = a_number
+            .len()
+            .cmp(&b_number.len())
+            .then_with(|| a_number.cmp(&b_number));
+        if order != Ordering::Equal {
+            return order;
+        }
+        a = &a[a_digits..];
+        b = &b[b_digits..];
+    }
+    Ordering::Equal
+}
+
+// Compares Debian package versions, `[epoch:]upstream[-revision]`
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let split = |version: &str| {
+        let (epoch, rest) = match version.split_once(':') {
+            Some((epoch, rest)) => (epoch.parse().unwrap_or(0u64), rest),
+            None => (0, version),
+        };
+        let (upstream, revision) = rest.rsplit_once('-').unwrap_or((rest, ""));
+        (epoch, upstream.to_string(), revision.to_string())
+    };
+    let (a_epoch, a_upstream, a_revision) = split(a);
+    let (b_epoch, b_upstream, b_revision) = split(b);
+    a_epoch
+        .cmp(&b_epoch)
+        .then_with(|| compare_part(&a_upstream, &b_upstream))
+        .then_with(|| compare_part(&a_revision, &b_revision))
+}
+
+// A package as recorded in dpkg's status database
+#[derive(Debug, Clone, PartialEq)]
+struct Installed {
+    version: String,
+    arch: String,
+}
+
+// The packages dpkg knows about
+#[derive(Debug, Default, PartialEq)]
+struct Status {
+    /// Keyed both by name and by `name:arch`
+    installed: HashMap<String, Installed>,
+    /// Removed packages whose configuration files were left behind
+    residual: HashSet<String>,
+}
+
+fn parse_status(status: &str) -> Status {
+    let mut packages = Status::default();
+    for paragraph in status.split("\n\n") {
+        let mut name = None;
+        let mut state = None;
+        let mut version = None;
+        let mut arch = None;
+        for line in paragraph.lines() {
+            let Some((field, value)) = line.split_once(':') else {
+                continue;
+            };
+            let value = value.trim();
+            match field {
+                "Package" => name = Some(value),
+                "Status" => state = value.split_whitespace().nth(2),
+                "Version" => version = Some(value),
+                "Architecture" => arch = Some(value),
+                _ => {}
+            }
+        }
+
+        if let (Some(name), Some("config-files")) = (name, state) {
+            packages.residual.insert(name.to_string());
+        }
+        if let (Some(name), Some("installed"), Some(version)) =
+            (name, state, version)
+        {
+            let package = Installed {
+                version: version.to_string(),
+                arch: arch.unwrap_or_default().to_string(),
+            };
+            if !package.arch.is_empty() {
+                let qualified = format!("{name}:{}", package.arch);
+                packages.installed.insert(qualified, package.clone());
+            }
+            packages.installed.insert(name.to_string(), package);
+        }
+    }
+    packages
+}
+
+fn read_status(path: &Path) -> Result<Status> {
+    let status = fs::read_to_string(path).map_err(|e| {
+        AptError::new(format!("Failed to read {}: {e}", path.display()))
+    })?;
+    Ok(parse_status(&status))
+}
+
+// Parses `apt-cache policy` into the candidate version of each package.
+// Packages without a candidate can't be installed.
+fn parse_policy(policy: &str) -> HashMap<String, String> {
+    let mut candidates = HashMap::new();
+    let mut package = None;
+    for line in policy.lines() {
+        if !line.starts_with(' ') {
+            package = line.strip_suffix(':');
+        } else if let Some((_, candidate)) = line.split_once("Candidate:") {
+            let candidate = candidate.trim();
+            if let Some(package) = package.filter(|_| candidate != "(none)") {
+                candidates.insert(package.to_string(), candidate.to_string());
+            }
+        }
+    }
+    candidates
+}
+
+// Without its architecture qualifier, as `apt-cache policy` reports it
+fn base_name(name: &str) -> &str {
+    name.split_once(':').map_or(name, |(name, _)| name)
+}
+
+fn candidates(names: &[String]) -> Result<HashMap<String, String>> {
+    if names.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let output = Command::new("apt-cache")
+        .env("LC_ALL", "C")
+        .arg("policy")
+        .args(names)
+        .output()?;
+    let output = check_apt_return(output)?;
+    Ok(parse_policy(&String::from_utf8(output.stdout)?))
+}
+
+// The packages apt needs to act on to reach the requested state
+#[derive(Debug, Default, PartialEq)]
+struct Plan {
+    install: Vec<String>,
+    remove: Vec<String>,
+}
+
+fn plan(
+    args: &AptArgs,
+    status: &Status,
+    candidates: &HashMap<String, String>,
+) -> Result<Plan> {
+    let mut plan = Plan::default();
+    let mut downgrades = vec![];
+    for spec in &args.names {
+        let (name, pinned) = split_version(spec);
+        let current = status.installed.get(name);
+        let matches = |current: &Installed| {
+            pinned
+                .is_none_or(|pinned| version_matches(pinned, &current.version))
+        };
+        match args.state {
+            State::Present | State::Fixed => match current {
+                Some(current) if matches(current) => {}
+                Some(current) => {
+                    // A wildcard is compared by the part before it
+                    let pinned = pinned.unwrap_or_default();
+                    let lowest = pinned.split('*').next().unwrap_or_default();
+                    if compare_versions(&current.version, lowest).is_gt() {
+                        downgrades.push(format!("{name} {}", current.version));
+                    }
+                    plan.install.push(spec.clone());
+                }
+                None => plan.install.push(spec.clone()),
+            },
+            State::Latest => {
+                let candidate = candidates.get(base_name(name));
+                let outdated = current.zip(candidate).is_some_and(
+                    |(current, candidate)| &current.version != candidate,
+                );
+                if current.is_none() || outdated {
+                    plan.install.push(name.to_string());
+                }
+            }
+            State::Absent => {
+                let residual = args.purge
+                    && pinned.is_none()
+                    && status.residual.contains(name);
+                if current.is_some_and(matches) || residual {
+                    plan.remove.push(name.to_string());
+                }
+            }
+        }
+    }
+
+    if !downgrades.is_empty() && !args.allow_downgrade {
+        return Err(AptError::new(format!(
+            "Pinned versions would downgrade {}, set `allow_downgrade: true` \
+             to allow this",
+            downgrades.join(", ")
+        )));
+    }
+    Ok(plan)
+}
+
+// Leaves only the packages in `plan` which dpkg's status shows were acted
+// on. apt-get skips some of what it's asked to do, such as held packages
+// and upgrades which can't be installed.
+fn carried_out(plan: Plan, before: &Status, after: &Status) -> Plan {
+    let differs = |name: &str| {
+        before.installed.get(name) != after.installed.get(name)
+            || before.residual.contains(name) != after.residual.contains(name)
+    };
+    Plan {
+        install: plan
+            .install
+            .into_iter()
+            .filter(|spec| differs(split_version(spec).0))
+            .collect(),
+        remove: plan
+            .remove
+            .into_iter()
+            .filter(|name| differs(name))
+            .collect(),
+    }
+}
+
+// The counts from the summary line apt-get prints, e.g. `1 upgraded, 2 newly
+// installed, 0 to remove and 5 not upgraded.`
+fn parse_summary(stdout: &str) -> Option<(u32, u32, u32)> {
+    let line = stdout.lines().find(|line| line.contains(" upgraded, "))?;
+    let mut counts = line
+        .split(", ")
+        .map(|part| part.split_whitespace().next()?.parse().ok());
+    Some((counts.next()??, counts.next()??, counts.next()??))
+}
+
+// The lock named in an error from apt, and who holds it when apt says so:
 //
+//     E: Could not get lock /var/lib/dpkg/lock-frontend. It is held by
+//     process 1234 (apt-get)
+fn parse_lock_error(stderr: &str) -> Option<(String, Option<String>)> {
+    let (_, rest) = stderr.split_once("Could not get lock ")?;
+    let lock = rest
+        .split(|c: char| c.is_whitespace())
+        .next()?
+        .trim_end_matches('.');
+    let holder = rest
+        .lines()
+        .next()?
+        .split_once("held by ")
+        .map(|(_, holder)| holder.trim().to_string());
+    Some((lock.to_string(), holder))
+}
 
-fn check_apt_return(output: std::process::Output) -> Result<()> {
+// Finds a process holding `lock` open, for apt versions which don't say
+fn lock_holder(lock: &Path) -> Option<String> {
+    for process in fs::read_dir("/proc").ok()?.flatten() {
+        let pid = process.file_name();
+        if !pid.to_string_lossy().bytes().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        let Ok(fds) = fs::read_dir(process.path().join("fd")) else {
+            continue;
+        };
+        let holds_lock = fds
+            .flatten()
+            .any(|fd| fs::read_link(fd.path()).is_ok_and(|path| path == lock));
+        if holds_lock {
+            let name = fs::read_to_string(process.path().join("comm"))
+                .unwrap_or_default();
+            return Some(format!(
+                "process {} ({})",
+                pid.to_string_lossy(),
+                name.trim()
+            ));
+        }
+    }
+    None
+}
+
+fn check_apt_return(output: Output) -> Result<Output> {
     match output.status.success() {
-        true => Ok(()),
+        true => Ok(output),
         false => {
             let stderr = String::from_utf8(output.stderr)?;
-            if stderr.contains("are you root?") {
-                return Err(AptError::from("Insufficient permissions"));
+            if let Some((lock, holder)) = parse_lock_error(&stderr) {
+                let holder = holder.or_else(|| lock_holder(Path::new(&lock)));
+                Err(AptError::Locked { lock, holder })
+            } else if stderr.contains("are you root?") {
+                Err(AptError::from("Insufficient permissions"))
             } else {
-                return Err(AptError::new(stderr));
+                Err(AptError::new(stderr.trim().to_string()))
             }
         }
     }
 }
 
-fn update_apt_cache() -> Result<()> {
-    let output = Command::new("apt-get").arg("update").arg("-y").output()?;
+// Makes attempts until one isn't stopped by a lock, or `timeout` runs out
+fn retry_locked<T>(
+    timeout: Duration,
+    interval: Duration,
+    mut attempt: impl FnMut() -> Result<T>,
+) -> Result<T> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match attempt() {
+            Err(AptError::Locked { .. }) if Instant::now() < deadline => {
+                thread::sleep(interval)
+            }
+            result => return result,
+        }
+    }
+}
 
-    check_apt_return(output)
+fn apt_get<I, S>(args: &AptArgs, options: I) -> Result<Output>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let options: Vec<OsString> = options
+        .into_iter()
+        .map(|option| option.as_ref().to_owned())
+        .collect();
+    let timeout = Duration::from_secs(args.lock_timeout);
+    retry_locked(timeout, Duration::from_secs(1), || {
+        let output = Command::new("apt-get")
+            .env("DEBIAN_FRONTEND", "noninteractive")
+            .env("LC_ALL", "C")
+            .args(&options)
+            .output()?;
+        check_apt_return(output)
+    })
 }
 
-fn install(packages: Vec<String>) -> Result<()> {
-    let output = Command::new("apt-get")
-        .arg("install")
-        .arg("-y")
-        .args(packages)
-        .output()?;
+// The arguments for an `apt-get` command which changes packages
+fn package_options(args: &AptArgs, command: &str) -> Vec<String> {
+    let mut options = vec![command.to_string(), "-y".to_string()];
+    for option in &args.dpkg_options {
+        options.push("-o".to_string());
+        options.push(format!("Dpkg::Options::=--{option}"));
+    }
+    match args.install_recommends {
+        Some(true) => options.push("--install-recommends".to_string()),
+        Some(false) => options.push("--no-install-recommends".to_string()),
+        None => {}
+    }
+    if args.allow_downgrade {
+        options.push("--allow-downgrades".to_string());
+    }
+    options
+}
 
-    check_apt_return(output)
+fn update_apt_cache(args: &AptArgs) -> Result<Output> {
+    apt_get(args, ["update", "-y"])
 }
 
-fn remove(packages: Vec<String>) -> Result<()> {
-    let output = Command::new("apt-get")
-        .arg("remove")
-        .arg("-y")
-        .args(packages)
-        .output()?;
+// When the package lists were last refreshed, if they ever were
+fn cache_update_time(stamps: &[&Path]) -> Option<SystemTime> {
+    stamps
+        .iter()
+        .filter_map(|stamp| fs::metadata(stamp).ok()?.modified().ok())
+        .max()
+}
 
-    check_apt_return(output)
+fn is_fresh(updated: SystemTime, valid_time: u64) -> bool {
+    // A time in the future is taken to be just now
+    let age = updated.elapsed().unwrap_or_default();
+    age < Duration::from_secs(valid_time)
 }
 
-fn upgrade(packages: Vec<String>) -> Result<()> {
-    let output = Command::new("apt-get")
-        .arg("upgrade")
-        .arg("-y")
-        .args(packages)
-        .output()?;
+// Refreshes the package lists unless they are recent enough. Returns
+// whether they were refreshed and when that last happened.
+fn update_cache(args: &AptArgs) -> Result<(bool, Option<SystemTime>)> {
+    let stamps: Vec<&Path> = CACHE_STAMPS.iter().map(Path::new).collect();
+    let updated = cache_update_time(&stamps);
+    if let (Some(updated), Some(valid_time)) = (updated, args.cache_valid_time)
+    {
+        if is_fresh(updated, valid_time) {
+            return Ok((false, Some(updated)));
+        }
+    }
 
-    check_apt_return(output)
+    update_apt_cache(args)?;
+    Ok((true, Some(SystemTime::now())))
 }
 
-// #[cfg(test)]
-// mod tests {
-//
-//     use std::{thread::sleep, time::Duration};
-//
-//     use super::*;
-//
-//     #[test]
-//     fn install_and_remove() {
-//         let package = "sl";
-//         let install_result = install_package(package);
-//         assert!(
-//             install_result.is_ok(),
-//             "result was not okay from install, got: {}",
-//             install_result.unwrap_err()
-//         );
-//         sleep(Duration::new(1, 0));
-//         let remove_result = remove_package(package);
-//         assert!(
-//             remove_result.is_ok(),
-//             "result was not okay from remove, got: {}",
-//             remove_result.unwrap_err()
-//         );
-//     }
-//
-//     #[test]
-//     fn update_cache() {
-//         let update_result = update_apt_cache();
-//         assert!(
-//             update_result.is_ok(),
-//             "result was not okay from update, got: {}",
-//             update_result.unwrap_err()
-//         );
-//     }
-// }
+fn install_options(packages: &[String], args: &AptArgs) -> Vec<String> {
+    let mut options = package_options(args, "install");
+    if args.state == State::Fixed {
+        options.push("-f".to_string());
+    }
+    options.extend(packages.iter().cloned());
+    options
+}
+
+fn remove_options(packages: &[String], args: &AptArgs) -> Vec<String> {
+    let command = match args.purge {
+        true => "purge",
+        false => "remove",
+    };
+    let mut options = package_options(args, command);
+    options.extend(packages.iter().cloned());
+    options
+}
+
+// Removes packages which were only installed as dependencies of packages
+// since removed. Returns whether there were any.
+fn autoremove(args: &AptArgs, check_mode: bool) -> Result<bool> {
+    let mut options = package_options(args, "autoremove");
+    if args.purge {
+        options.push("--purge".to_string());
+    }
+    if check_mode {
+        return Ok(!Simulation::run(args, &options)?.removed.is_empty());
+    }
+    let output = apt_get(args, options)?;
+    let summary = parse_summary(&String::from_utf8_lossy(&output.stdout));
+    Ok(summary.is_some_and(|(_, _, removed)| removed > 0))
+}
+
+// Deletes cached packages which can no longer be downloaded. Returns
+// whether there were any.
+fn autoclean(args: &AptArgs, check_mode: bool) -> Result<bool> {
+    let mut options = vec!["autoclean", "-y"];
+    if check_mode {
+        options.push("--simulate");
+    }
+    let output = apt_get(args, options)?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout.lines().any(|line| line.starts_with("Del ")))
+}
+
+fn yaml_list(items: &[String]) -> Yaml {
+    Yaml::Array(items.iter().cloned().map(Yaml::String).collect())
+}
+
+// Marks the result as changed, adding `msg` to what it already did
+fn note_change(result: &mut TaskResult, msg: &str) {
+    match result.changed {
+        true => result.msg = format!("{}, {msg}", result.msg),
+        false => result.msg = msg.to_string(),
+    }
+    result.changed = true;
+}
+
+fn run_upgrade(
+    args: &AptArgs,
+    upgrade: Upgrade,
+    check_mode: bool,
+) -> Result<TaskResult> {
+    let command = match upgrade {
+        Upgrade::Safe => "upgrade",
+        Upgrade::Full => "dist-upgrade",
+    };
+    let options = package_options(args, command);
+    let simulation = Simulation::run(args, &options)?;
+    if simulation.is_empty() {
+        return Ok(
+            TaskResult::new(false).with_msg("All packages are up to date")
+        );
+    }
+
+    let count = simulation.upgraded.len();
+    let mut result = match check_mode {
+        true => TaskResult::new(true)
+            .with_msg(format!("Would upgrade {count} packages")),
+        false => TaskResult::new(true)
+            .with_output(&apt_get(args, &options)?)
+            .with_msg(format!("Upgraded {count} packages")),
+    };
+    result.set("upgraded", yaml_changes(&simulation.upgraded));
+    result.set("installed", yaml_changes(&simulation.installed));
+    result.set("removed", yaml_changes(&simulation.removed));
+    Ok(result)
+}
+
+// Does the work, or in check mode reports what apt would do from simulated
+// runs. The cache is not updated in check mode.
+fn run(args: &AptArgs, check_mode: bool) -> Result<TaskResult> {
+    let cache = match args.update_cache && !check_mode {
+        true => Some(update_cache(args)?),
+        false => None,
+    };
+
+    let mut result = match (&args.deb, args.upgrade) {
+        (Some(deb), _) => deb::run(args, deb, check_mode)?,
+        (None, Some(upgrade)) => run_upgrade(args, upgrade, check_mode)?,
+        (None, None) => run_packages(args, check_mode)?,
+    };
+    if args.autoremove && autoremove(args, check_mode)? {
+        note_change(
+            &mut result,
+            match check_mode {
+                true => "Would remove unneeded dependencies",
+                false => "Removed unneeded dependencies",
+            },
+        );
+    }
+    if args.autoclean && autoclean(args, check_mode)? {
+        note_change(
+            &mut result,
+            match check_mode {
+                true => "Would delete obsolete cached packages",
+                false => "Deleted obsolete cached packages",
+            },
+        );
+    }
+    if let Some((updated, update_time)) = cache {
+        let seconds = update_time
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs().try_into().unwrap_or(i64::MAX));
+        result.set("cache_updated", Yaml::Boolean(updated));
+        result.set(
+            "cache_update_time",
+            seconds.map_or(Yaml::Null, Yaml::Integer),
+        );
+    }
+    Ok(result)
+}
+
+fn run_packages(args: &AptArgs, check_mode: bool) -> Result<TaskResult> {
+    let status = read_status(Path::new(DPKG_STATUS))?;
+    let candidates = match args.state {
+        State::Latest => candidates(&args.names)?,
+        _ => HashMap::new(),
+    };
+    let mut plan = plan(args, &status, &candidates)?;
+
+    let options = if !plan.install.is_empty() || args.state == State::Fixed {
+        Some(install_options(&plan.install, args))
+    } else if !plan.remove.is_empty() {
+        Some(remove_options(&plan.remove, args))
+    } else {
+        None
+    };
+    let mut result = TaskResult::new(false);
+    let mut status_after = None;
+    match options {
+        Some(options) if check_mode => {
+            let simulation = Simulation::run(args, &options)?;
+            result.changed = !plan.install.is_empty()
+                || !plan.remove.is_empty()
+                || !simulation.is_empty();
+            result.set("simulation", simulation.to_yaml());
+        }
+        Some(options) => {
+            let output = apt_get(args, options)?;
+            let after = read_status(Path::new(DPKG_STATUS))?;
+            plan = carried_out(plan, &status, &after);
+            result.changed = after != status;
+            result = result.with_output(&output);
+            status_after = Some(after);
+        }
+        None => {}
+    }
+
+    let (installed, removed, fixed) = match check_mode {
+        true => ("Would install", "Would remove", "Would fix"),
+        false => ("Installed", "Removed", "Fixed"),
+    };
+    result.msg = match (plan.install.is_empty(), plan.remove.is_empty()) {
+        (false, _) => format!("{installed} {}", plan.install.join(", ")),
+        (_, false) => format!("{removed} {}", plan.remove.join(", ")),
+        _ if result.changed => format!("{fixed} broken dependencies"),
+        _ => "All packages are in the requested state".to_string(),
+    };
+    result.set("installed", yaml_list(&plan.install));
+    result.set("removed", yaml_list(&plan.remove));
+
+    let installed = status_after.unwrap_or(status).installed;
+    let mut versions = Hash::new();
+    for spec in &args.names {
+        let (name, _) = split_version(spec);
+        let version = installed.get(name).map(|package| &package.version);
+        versions.insert(
+            Yaml::String(name.to_string()),
+            version.map_or(Yaml::Null, |version| Yaml::String(version.clone())),
+        );
+    }
+    result.set("versions", Yaml::Hash(versions));
+    Ok(result)
+}
+
+pub struct Apt;
+
+impl Module for Apt {
+    type Args = AptArgs;
+
+    fn parse_args(&self, args: &Yaml) -> super::Result<Self::Args> {
+        let args = Args::new("apt", args, OPTIONS)?;
+        let mut apt_args = AptArgs::new(args.list("name")?.unwrap_or_default());
+        apt_args.state = match args
+            .choice("state", &["present", "absent", "latest", "fixed"])?
+            .as_deref()
+        {
+            Some("absent") => State::Absent,
+            Some("latest") => State::Latest,
+            Some("fixed") => State::Fixed,
+            _ => State::Present,
+        };
+        apt_args.cache_valid_time = match args.int("cache_valid_time")? {
+            Some(seconds) => Some(seconds.try_into().map_err(|_| {
+                ModuleError::PlainMessage(
+                    "apt: `cache_valid_time` must not be negative".to_string(),
+                )
+            })?),
+            None => None,
+        };
+        // Giving a validity implies updating once it runs out
+        apt_args.update_cache = args
+            .bool("update_cache")?
+            .unwrap_or(apt_args.cache_valid_time.is_some());
+        apt_args.allow_downgrade =
+            args.bool("allow_downgrade")?.unwrap_or(false);
+        if apt_args.state == State::Latest
+            && apt_args.names.iter().any(|name| name.contains('='))
+        {
+            return Err(ModuleError::PlainMessage(
+                "apt: versions can't be pinned with `state: latest`"
+                    .to_string(),
+            ));
+        }
+        apt_args.deb = args.string("deb")?;
+        if apt_args.deb.is_some()
+            && (!apt_args.names.is_empty() || apt_args.state != State::Present)
+        {
+            return Err(ModuleError::PlainMessage(
+                "apt: `deb` can't be combined with `name` or a `state` other \
+                 than present"
+                    .to_string(),
+            ));
+        }
+        apt_args.purge = args.bool("purge")?.unwrap_or(false);
+        apt_args.autoremove = args.bool("autoremove")?.unwrap_or(false);
+        apt_args.autoclean = args.bool("autoclean")?.unwrap_or(false);
+        apt_args.install_recommends = args.bool("install_recommends")?;
+        if let Some(options) = args.list("dpkg_options")? {
+            apt_args.dpkg_options = options;
+        }
+        if let Some(timeout) = args.int("lock_timeout")? {
+            apt_args.lock_timeout = timeout.try_into().map_err(|_| {
+                ModuleError::PlainMessage(
+                    "apt: `lock_timeout` must not be negative".to_string(),
+                )
+            })?;
+        }
+        apt_args.upgrade = match args
+            .choice("upgrade", &["no", "yes", "safe", "full", "dist"])?
+            .as_deref()
+        {
+            Some("yes" | "safe") => Some(Upgrade::Safe),
+            Some("full" | "dist") => Some(Upgrade::Full),
+            _ => None,
+        };
+        if apt_args.upgrade.is_some()
+            && (!apt_args.names.is_empty() || apt_args.deb.is_some())
+        {
+            return Err(ModuleError::PlainMessage(
+                "apt: `upgrade` can't be combined with `name` or `deb`"
+                    .to_string(),
+            ));
+        }
+        if apt_args.names.is_empty()
+            && apt_args.deb.is_none()
+            && !apt_args.update_cache
+            && !apt_args.autoremove
+            && !apt_args.autoclean
+            && apt_args.upgrade.is_none()
+            && apt_args.state != State::Fixed
+        {
+            return Err(ModuleError::PlainMessage(
+                "apt: one of `name`, `deb`, `upgrade`, `update_cache`, \
+                 `autoremove` or `autoclean` is required"
+                    .to_string(),
+            ));
+        }
+        Ok(apt_args)
+    }
+
+    fn run(&self, args: Self::Args) -> super::Result<TaskResult> {
+        task_result(run(&args, false))
+    }
+
+    fn check(&self, args: Self::Args) -> super::Result<TaskResult> {
+        task_result(run(&args, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    const STATUS: &str = "\
+Package: bash
+Status: install ok installed
+Architecture: amd64
+Version: 5.2.15-2+b8
+Description: GNU Bourne Again SHell
+ Bash is an sh-compatible command language interpreter.
+
+Package: nano
+Status: deinstall ok config-files
+Architecture: amd64
+Version: 7.2-1
+
+Package: tzdata
+Status: install ok installed
+Architecture: all
+Version: 2024a-0+deb12u1
+";
+
+    const POLICY: &str = "\
+bash:
+  Installed: 5.2.15-2+b8
+  Candidate: 5.2.15-2+b13
+  Version table:
+     5.2.15-2+b13 500
+        500 http://deb.debian.org/debian bookworm/main amd64 Packages
+ *** 5.2.15-2+b8 100
+        100 /var/lib/dpkg/status
+tzdata:
+  Installed: 2024a-0+deb12u1
+  Candidate: 2024a-0+deb12u1
+  Version table:
+ *** 2024a-0+deb12u1 500
+        500 http://deb.debian.org/debian bookworm/main amd64 Packages
+nano:
+  Installed: (none)
+  Candidate: (none)
+  Version table:
+";
+
+    fn names(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn apt_parse_status() {
+        let status = parse_status(STATUS);
+        assert!(status.residual.contains("nano"));
+        let installed = status.installed;
+        assert_eq!(installed["bash"].version, "5.2.15-2+b8");
+        assert_eq!(installed["bash:amd64"].version, "5.2.15-2+b8");
+        assert_eq!(installed["tzdata:all"].arch, "all");
+        assert!(!installed.contains_key("nano"));
+    }
+
+    #[test]
+    fn apt_parse_policy() {
+        let candidates = parse_policy(POLICY);
+        assert_eq!(candidates["bash"], "5.2.15-2+b13");
+        assert_eq!(candidates["tzdata"], "2024a-0+deb12u1");
+        assert!(!candidates.contains_key("nano"));
+    }
+
+    #[test]
+    fn apt_plan() {
+        let status = parse_status(STATUS);
+        let candidates = parse_policy(POLICY);
+        let mut args = AptArgs::new(names(&["bash:amd64", "tzdata", "nano"]));
+
+        let planned = plan(&args, &status, &candidates).unwrap();
+        assert_eq!(planned.install, names(&["nano"]));
+        assert!(planned.remove.is_empty());
+
+        args.state = State::Latest;
+        let planned = plan(&args, &status, &candidates).unwrap();
+        assert_eq!(planned.install, names(&["bash:amd64", "nano"]));
+
+        args.state = State::Absent;
+        let planned = plan(&args, &status, &candidates).unwrap();
+        assert!(planned.install.is_empty());
+        assert_eq!(planned.remove, names(&["bash:amd64", "tzdata"]));
+    }
+
+    #[test]
+    fn apt_versions() {
+        use std::cmp::Ordering::*;
+        for (a, b, order) in [
+            ("1.0", "1.0", Equal),
+            ("1.0", "1.00", Equal),
+            ("1.2.10", "1.2.9", Greater),
+            ("1.0~rc1", "1.0", Less),
+            ("1.0", "1.0+b1", Less),
+            ("1.0a", "1.0", Greater),
+            ("1:0.9", "2.0", Greater),
+            ("2.0-1", "2.0-10", Less),
+            ("5.2.15-2+b8", "5.2.15-2+b13", Less),
+        ] {
+            assert_eq!(compare_versions(a, b), order, "{a} {b}");
+            assert_eq!(compare_versions(b, a), order.reverse(), "{b} {a}");
+        }
+
+        assert!(version_matches("1.2.3", "1.2.3"));
+        assert!(!version_matches("1.2.3", "1.2.30"));
+        assert!(version_matches("1.2.*", "1.2.30-1"));
+        assert!(!version_matches("1.2.*", "1.3.0"));
+        assert!(version_matches("1.*-1", "1.4-1"));
+        assert_eq!(split_version("bash=5.2*"), ("bash", Some("5.2*")));
+        assert_eq!(split_version("bash"), ("bash", None));
+    }
+
+    #[test]
+    fn apt_plan_versions() {
+        let status = parse_status(STATUS);
+        let candidates = HashMap::new();
+        let mut args = AptArgs::new(names(&[
+            "bash=5.2.*",
+            "tzdata=2025a-0+deb12u1",
+            "nano=7.2-1",
+        ]));
+
+        let planned = plan(&args, &status, &candidates).unwrap();
+        assert_eq!(
+            planned.install,
+            names(&["tzdata=2025a-0+deb12u1", "nano=7.2-1"])
+        );
+
+        args.names = names(&["bash=5.1*"]);
+        let error = plan(&args, &status, &candidates).unwrap_err();
+        assert!(error.to_string().contains("bash 5.2.15-2+b8"), "{error}");
+        args.allow_downgrade = true;
+        let planned = plan(&args, &status, &candidates).unwrap();
+        assert_eq!(planned.install, names(&["bash=5.1*"]));
+
+        args.state = State::Absent;
+        assert!(plan(&args, &status, &candidates).unwrap().remove.is_empty());
+        args.names = names(&["bash=5.2.15-2+b8"]);
+        let planned = plan(&args, &status, &candidates).unwrap();
+        assert_eq!(planned.remove, names(&["bash"]));
+    }
+
+    #[test]
+    fn apt_purge() {
+        let status = parse_status(STATUS);
+        let mut args = AptArgs::new(names(&["bash", "nano"]));
+        args.state = State::Absent;
+        let planned = plan(&args, &status, &HashMap::new()).unwrap();
+        assert_eq!(planned.remove, names(&["bash"]));
+
+        args.purge = true;
+        let planned = plan(&args, &status, &HashMap::new()).unwrap();
+        assert_eq!(planned.remove, names(&["bash", "nano"]));
+    }
+
+    #[test]
+    fn apt_carried_out() {
+        let before = parse_status(STATUS);
+        // bash was upgraded, tzdata is held and nano couldn't be installed
+        let after =
+            parse_status(&STATUS.replace("5.2.15-2+b8", "5.2.15-2+b13"));
+        let planned = Plan {
+            install: names(&["bash:amd64", "tzdata", "nano=7.2-1"]),
+            remove: vec![],
+        };
+        let done = carried_out(planned, &before, &after);
+        assert_eq!(done.install, names(&["bash:amd64"]));
+
+        let planned = Plan {
+            install: names(&["tzdata"]),
+            remove: names(&["nano"]),
+        };
+        assert_eq!(carried_out(planned, &before, &before), Plan::default());
+        let after = parse_status(
+            &STATUS.replace("ok config-files", "ok not-installed"),
+        );
+        let planned = Plan {
+            install: vec![],
+            remove: names(&["bash", "nano"]),
+        };
+        let done = carried_out(planned, &before, &after);
+        assert_eq!(done.remove, names(&["nano"]));
+    }
+
+    #[test]
+    fn apt_package_options() {
+        let mut args = AptArgs::new(names(&["git"]));
+        assert_eq!(
+            package_options(&args, "install"),
+            [
+                "install",
+                "-y",
+                "-o",
+                "Dpkg::Options::=--force-confdef",
+                "-o",
+                "Dpkg::Options::=--force-confold"
+            ]
+        );
+
+        args.dpkg_options = names(&["force-confnew"]);
+        args.install_recommends = Some(false);
+        args.allow_downgrade = true;
+        assert_eq!(
+            package_options(&args, "install"),
+            [
+                "install",
+                "-y",
+                "-o",
+                "Dpkg::Options::=--force-confnew",
+                "--no-install-recommends",
+                "--allow-downgrades"
+            ]
+        );
+    }
+
+    #[test]
+    fn apt_cache_valid_time() {
+        let tmp = tempfile::tempdir().unwrap();
+        let stamp = tmp.path().join("update-success-stamp");
+        let lists = tmp.path().join("lists");
+        assert_eq!(cache_update_time(&[&stamp, &lists]), None);
+
+        let hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        fs::File::create(&stamp)
+            .unwrap()
+            .set_modified(hour_ago)
+            .unwrap();
+        fs::create_dir(&lists).unwrap();
+        let updated = cache_update_time(&[&stamp, &lists]).unwrap();
+        assert!(updated > hour_ago);
+        assert_eq!(cache_update_time(&[&stamp]), Some(hour_ago));
+
+        assert!(is_fresh(hour_ago, 7200));
+        assert!(!is_fresh(hour_ago, 600));
+        assert!(is_fresh(SystemTime::now() + Duration::from_secs(60), 1));
+    }
+
+    #[test]
+    fn apt_lock() {
+        let stderr = "E: Could not get lock /var/lib/dpkg/lock-frontend. It \
+                      is held by process 1234 (apt-get)\n\
+                      N: Be aware that removing the lock file is not a \
+                      solution and may break your system.\n\
+                      E: Unable to acquire the dpkg frontend lock \
+                      (/var/lib/dpkg/lock-frontend), is another process \
+                      using it?\n";
+        assert_eq!(
+            parse_lock_error(stderr),
+            Some((
+                "/var/lib/dpkg/lock-frontend".to_string(),
+                Some("process 1234 (apt-get)".to_string())
+            ))
+        );
+        let stderr = "E: Could not get lock /var/lib/dpkg/lock - open (11: \
+                      Resource temporarily unavailable)\n";
+        assert_eq!(
+            parse_lock_error(stderr),
+            Some(("/var/lib/dpkg/lock".to_string(), None))
+        );
+        assert_eq!(parse_lock_error("E: Unable to locate package foo"), None);
+
+        let tmp = tempfile::tempdir().unwrap();
+        let lock = tmp.path().join("lock");
+        let _file = fs::File::create(&lock).unwrap();
+        let holder = lock_holder(&lock).unwrap();
+        assert!(
+            holder.starts_with(&format!("process {} (", std::process::id())),
+            "{holder}"
+        );
+        assert_eq!(lock_holder(&tmp.path().join("missing")), None);
+    }
+
+    #[test]
+    fn apt_retry_locked() {
+        let locked = || AptError::Locked {
+            lock: "/var/lib/dpkg/lock".to_string(),
+            holder: Some("process 1 (apt-get)".to_string()),
+        };
+        let interval = Duration::from_millis(1);
+
+        let mut attempts = 0;
+        let result = retry_locked(Duration::from_secs(60), interval, || {
+            attempts += 1;
+            match attempts {
+                1 | 2 => Err(locked()),
+                _ => Ok(attempts),
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let result: Result<()> =
+            retry_locked(Duration::from_millis(20), interval, || Err(locked()));
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Error during `apt-get` operation: Timed out waiting for the lock \
+             on /var/lib/dpkg/lock, held by process 1 (apt-get)"
+        );
+
+        let mut attempts = 0;
+        let result: Result<()> = retry_locked(Duration::ZERO, interval, || {
+            attempts += 1;
+            Err(AptError::new("failed".to_string()))
+        });
+        assert!(matches!(result, Err(AptError::Failed(_))));
+        assert_eq!(attempts, 1);
+
+        let result = task_result(Err(locked())).unwrap();
+        assert!(result.failed);
+        assert_eq!(
+            result.get("lock_holder"),
+            Some(&Yaml::String("process 1 (apt-get)".to_string()))
+        );
+        assert!(task_result(Err(AptError::new("failed".to_string()))).is_err());
+    }
+
+    #[test]
+    fn apt_parse_summary() {
+        let stdout = "Reading package lists...\n\
+            0 upgraded, 2 newly installed, 1 to remove and 5 not upgraded.\n";
+        assert_eq!(parse_summary(stdout), Some((0, 2, 1)));
+        assert_eq!(parse_summary("Reading package lists...\n"), None);
+    }
+
+    #[test]
+    fn apt_args() {
+        let load =
+            |source| YamlLoader::load_from_str(source).unwrap().remove(0);
+
+        let args = Apt.parse_args(&load("name: [git, curl]")).unwrap();
+        assert_eq!(args, AptArgs::new(names(&["git", "curl"])));
+
+        let args = Apt.parse_args(&load("name: git\nstate: latest")).unwrap();
+        assert_eq!(args.state, State::Latest);
+
+        assert!(Apt.parse_args(&load("update_cache: yes")).is_ok());
+        let args = Apt.parse_args(&load("cache_valid_time: 3600")).unwrap();
+        assert!(args.update_cache);
+        assert_eq!(args.cache_valid_time, Some(3600));
+        assert!(Apt.parse_args(&load("cache_valid_time: -1")).is_err());
+        let args = Apt.parse_args(&load("name: git\nlock_timeout: 5")).unwrap();
+        assert_eq!(args.lock_timeout, 5);
+        let args = Apt.parse_args(&load("deb: ./tool.deb")).unwrap();
+        assert_eq!(args.deb.as_deref(), Some("./tool.deb"));
+        assert!(Apt.parse_args(&load("deb: a.deb\nname: git")).is_err());
+        let args = Apt
+            .parse_args(&load(
+                "autoremove: yes\ninstall_recommends: no\n\
+                 dpkg_options: force-confold,force-unsafe-io",
+            ))
+            .unwrap();
+        assert!(args.autoremove);
+        assert_eq!(args.install_recommends, Some(false));
+        assert_eq!(
+            args.dpkg_options,
+            names(&["force-confold", "force-unsafe-io"])
+        );
+
+        let args = Apt.parse_args(&load("upgrade: dist")).unwrap();
+        assert_eq!(args.upgrade, Some(Upgrade::Full));
+        let args = Apt.parse_args(&load("upgrade: yes")).unwrap();
+        assert_eq!(args.upgrade, Some(Upgrade::Safe));
+        assert!(Apt.parse_args(&load("upgrade: no")).is_err());
+        assert!(Apt.parse_args(&load("upgrade: safe\nname: git")).is_err());
+        assert!(Apt.parse_args(&load("deb: a.deb\nstate: absent")).is_err());
+        assert!(Apt.parse_args(&load("state: absent")).is_err());
+        assert!(Apt.parse_args(&load("name: git\nstate: gone")).is_err());
+        assert!(Apt.parse_args(&load("name: git=1\nstate: latest")).is_err());
+    }
+}
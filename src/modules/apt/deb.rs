@@ -0,0 +1,225 @@
+// Installs a package from a `.deb` file, either local or downloaded, letting
+// apt resolve its dependencies.
+use super::{
+    apt_get, compare_versions, package_options, read_status, yaml_list,
+    AptArgs, AptError, Installed, Result, Simulation, DPKG_STATUS,
+};
+use crate::modules::temp::{is_url, Download};
+use crate::modules::TaskResult;
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::process::Command;
+
+// The control fields of a package file which decide whether it is installed
+#[derive(Debug, Clone, PartialEq)]
+struct Control {
+    name: String,
+    version: String,
+    arch: String,
+    depends: Option<String>,
+}
+
+impl Control {
+    fn read(deb: &Path) -> Result<Self> {
+        let output = Command::new("dpkg-deb")
+            .arg("--field")
+            .arg(deb)
+            .args(["Package", "Version", "Architecture", "Depends"])
+            .output()?;
+        if !output.status.success() {
+            return Err(AptError::new(format!(
+                "Failed to read {}: {}",
+                deb.display(),
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Self::parse(&String::from_utf8(output.stdout)?).ok_or_else(|| {
+            AptError::new(format!(
+                "{} is missing its package name or version",
+                deb.display()
+            ))
+        })
+    }
+
+    fn parse(fields: &str) -> Option<Self> {
+        let mut values: HashMap<&str, String> = HashMap::new();
+        let mut last = None;
+        for line in fields.lines() {
+            if line.starts_with([' ', '\t']) {
+                // Continues the previous field, as long dependencies do
+                if let Some(value) = last.and_then(|key| values.get_mut(key)) {
+                    value.push(' ');
+                    value.push_str(line.trim());
+                }
+            } else if let Some((key, value)) = line.split_once(':') {
+                values.insert(key, value.trim().to_string());
+                last = Some(key);
+            }
+        }
+
+        Some(Self {
+            name: values.remove("Package")?,
+            version: values.remove("Version")?,
+            arch: values.remove("Architecture").unwrap_or_default(),
+            depends: values.remove("Depends"),
+        })
+    }
+
+    fn installed<'a>(
+        &self,
+        installed: &'a HashMap<String, Installed>,
+    ) -> Option<&'a Installed> {
+        let qualified = format!("{}:{}", self.name, self.arch);
+        installed
+            .get(&qualified)
+            .or_else(|| match self.arch.as_str() {
+                "all" | "" => installed.get(&self.name),
+                _ => None,
+            })
+    }
+}
+
+// Whether the package needs installing, failing when that would downgrade
+// it without permission
+fn needs_install(
+    control: &Control,
+    installed: &HashMap<String, Installed>,
+    allow_downgrade: bool,
+) -> Result<bool> {
+    let Some(current) = control.installed(installed) else {
+        return Ok(true);
+    };
+    if current.version == control.version {
+        return Ok(false);
+    }
+    if compare_versions(&current.version, &control.version).is_gt()
+        && !allow_downgrade
+    {
+        return Err(AptError::new(format!(
+            "Installing {} {} would downgrade it from {}, set \
+             `allow_downgrade: true` to allow this",
+            control.name, control.version, current.version
+        )));
+    }
+    Ok(true)
+}
+
+pub fn run(args: &AptArgs, deb: &str, check_mode: bool) -> Result<TaskResult> {
+    // apt only takes the argument for a file when it looks like a path to
+    // a `.deb`
+    let download = match is_url(deb) {
+        true => Some(Download::fetch(deb, Some("package.deb"))?),
+        false => None,
+    };
+    let path = match &download {
+        Some(download) => download.path().to_path_buf(),
+        None => std::path::absolute(deb)?,
+    };
+    let control = Control::read(&path)?;
+
+    let installed = read_status(Path::new(DPKG_STATUS))?.installed;
+    let mut result = TaskResult::new(false);
+    let changed = needs_install(&control, &installed, args.allow_downgrade)?;
+    let mut options = package_options(args, "install");
+    options.push(path.display().to_string());
+    if changed && check_mode {
+        // Finds out whether the dependencies can be satisfied
+        let simulation = Simulation::run(args, &options)?;
+        result = TaskResult::new(true);
+        result.set("simulation", simulation.to_yaml());
+    } else if changed {
+        let output = apt_get(args, options)?;
+        result = TaskResult::new(true).with_output(&output);
+    }
+
+    let spec = format!("{}={}", control.name, control.version);
+    result.msg = match (changed, check_mode) {
+        (true, true) => format!("Would install {spec} from {deb}"),
+        (true, false) => format!("Installed {spec} from {deb}"),
+        (false, _) => format!("{spec} is already installed"),
+    };
+    let installed = match changed {
+        true => vec![spec],
+        false => vec![],
+    };
+    result.set("installed", yaml_list(&installed));
+    let mut versions = Hash::new();
+    versions.insert(Yaml::String(control.name), Yaml::String(control.version));
+    result.set("versions", Yaml::Hash(versions));
+    if let Some(depends) = control.depends {
+        result.set("depends", Yaml::String(depends));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::PathBuf;
+
+    fn build_deb(dir: &Path, version: &str) -> PathBuf {
+        let root = dir.join("pkg");
+        fs::create_dir_all(root.join("DEBIAN")).unwrap();
+        fs::write(
+            root.join("DEBIAN/control"),
+            format!(
+                "Package: rustible-test\nVersion: {version}\nArchitecture: \
+                 all\nMaintainer: Test <test@example.com>\nDepends: libc6,\n \
+                 tzdata\nDescription: test package\n"
+            ),
+        )
+        .unwrap();
+        let deb = dir.join(format!("rustible-test_{version}.deb"));
+        let output = Command::new("dpkg-deb")
+            .args(["--root-owner-group", "--build"])
+            .arg(&root)
+            .arg(&deb)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{output:?}");
+        deb
+    }
+
+    #[test]
+    fn deb_control() {
+        let tmp = tempfile::tempdir().unwrap();
+        let deb = build_deb(tmp.path(), "1.2-1");
+        let control = Control::read(&deb).unwrap();
+        assert_eq!(
+            control,
+            Control {
+                name: "rustible-test".to_string(),
+                version: "1.2-1".to_string(),
+                arch: "all".to_string(),
+                depends: Some("libc6, tzdata".to_string()),
+            }
+        );
+        assert!(Control::read(&tmp.path().join("missing.deb")).is_err());
+    }
+
+    #[test]
+    fn deb_needs_install() {
+        let control = Control::parse(
+            "Package: tool\nVersion: 1.2-1\nArchitecture: amd64\n",
+        )
+        .unwrap();
+        let mut installed = HashMap::new();
+        assert!(needs_install(&control, &installed, false).unwrap());
+
+        let package = |version: &str| Installed {
+            version: version.to_string(),
+            arch: "amd64".to_string(),
+        };
+        installed.insert("tool:amd64".to_string(), package("1.2-1"));
+        assert!(!needs_install(&control, &installed, false).unwrap());
+        installed.insert("tool:amd64".to_string(), package("1.1-1"));
+        assert!(needs_install(&control, &installed, false).unwrap());
+        installed.insert("tool:amd64".to_string(), package("1.3-1"));
+        assert!(needs_install(&control, &installed, false).is_err());
+        assert!(needs_install(&control, &installed, true).unwrap());
+    }
+}
@@ -0,0 +1,541 @@
+// Manages apt sources in `sources.list.d`, either as one-line `.list` files
+// or deb822 `.sources` files, and the keyrings they are signed by
+use super::{update_apt_cache, AptArgs};
+use crate::modules::args::Args;
+use crate::modules::{Module, ModuleError, Result, TaskResult};
+use yaml_rust::Yaml;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const OPTIONS: &[&str] = &[
+    "repo",
+    "state",
+    "filename",
+    "format",
+    "key",
+    "signed_by",
+    "update_cache",
+];
+
+const ARMORED_KEY: &str = "-----BEGIN PGP PUBLIC KEY BLOCK-----";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    /// `deb [options] uri suite components` lines in a `.list` file
+    OneLine,
+    /// A `.sources` file holding a single deb822 stanza
+    Deb822,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct AptRepositoryArgs {
+    /// The source as a one-line entry, also for deb822 files
+    pub repo: String,
+    /// Whether the source should be configured
+    pub present: bool,
+    /// Name of the file in `sources.list.d`, without its extension.
+    /// Derived from the repository's URI by default
+    pub filename: Option<String>,
+    pub format: Format,
+    /// Path to a key, or an ASCII-armored key, to install into
+    /// `/etc/apt/keyrings` and sign the source by
+    pub key: Option<String>,
+    /// Keyring to sign the source by, overriding the one `key` goes into
+    pub signed_by: Option<String>,
+    /// Run `apt-get update` when anything changed
+    pub update_cache: bool,
+    /// The directory `/etc` is found in
+    pub root: PathBuf,
+}
+
+impl AptRepositoryArgs {
+    pub fn new(repo: impl Into<String>) -> Self {
+        Self {
+            repo: repo.into(),
+            present: true,
+            filename: None,
+            format: Format::OneLine,
+            key: None,
+            signed_by: None,
+            update_cache: true,
+            root: PathBuf::from("/"),
+        }
+    }
+}
+
+fn invalid(msg: String) -> ModuleError {
+    ModuleError::PlainMessage(format!("apt_repository: {msg}"))
+}
+
+// A one-line source entry, `deb [arch=amd64] http://example.com stable main`
+#[derive(Debug, Clone, PartialEq)]
+struct Source {
+    kind: String,
+    options: Vec<(String, String)>,
+    uri: String,
+    suite: String,
+    components: Vec<String>,
+}
+
+impl Source {
+    fn parse(line: &str) -> Option<Self> {
+        let line = line.trim();
+        let (kind, rest) = line.split_once(char::is_whitespace)?;
+        if kind != "deb" && kind != "deb-src" {
+            return None;
+        }
+
+        let mut options = vec![];
+        let mut rest = rest.trim_start();
+        if let Some(bracketed) = rest.strip_prefix('[') {
+            let (list, after) = bracketed.split_once(']')?;
+            for option in list.split_whitespace() {
+                let (key, value) = option.split_once('=')?;
+                options.push((key.to_string(), value.to_string()));
+            }
+            rest = after;
+        }
+
+        let mut words = rest.split_whitespace();
+        Some(Self {
+            kind: kind.to_string(),
+            options,
+            uri: words.next()?.to_string(),
+            suite: words.next()?.to_string(),
+            components: words.map(str::to_string).collect(),
+        })
+    }
+
+    // Whether both name the same repository, whatever their options
+    fn same_repo(&self, other: &Self) -> bool {
+        self.kind == other.kind
+            && self.uri.trim_end_matches('/') == other.uri.trim_end_matches('/')
+            && self.suite == other.suite
+            && self.components == other.components
+    }
+
+    fn set_option(&mut self, key: &str, value: &str) {
+        match self.options.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.options.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        let mut line = self.kind.clone();
+        if !self.options.is_empty() {
+            let options: Vec<String> = self
+                .options
+                .iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .collect();
+            line.push_str(&format!(" [{}]", options.join(" ")));
+        }
+        line.push_str(&format!(" {} {}", self.uri, self.suite));
+        for component in &self.components {
+            line.push(' ');
+            line.push_str(component);
+        }
+        line
+    }
+
+    fn to_deb822(&self) -> String {
+        let mut stanza = format!(
+            "Types: {}\nURIs: {}\nSuites: {}\n",
+            self.kind, self.uri, self.suite
+        );
+        if !self.components.is_empty() {
+            stanza.push_str(&format!(
+                "Components: {}\n",
+                self.components.join(" ")
+            ));
+        }
+        for (key, value) in &self.options {
+            let field = match key.as_str() {
+                "arch" => "Architectures",
+                "lang" => "Languages",
+                "target" => "Targets",
+                "pdiffs" => "PDiffs",
+                "by-hash" => "By-Hash",
+                "trusted" => "Trusted",
+                "signed-by" => "Signed-By",
+                other => other,
+            };
+            // Lists are comma-separated in one-line entries
+            let value = value.replace(',', " ");
+            stanza.push_str(&format!("{field}: {value}\n"));
+        }
+        stanza
+    }
+
+    // A file name from the URI, `http://example.com/apt/` → `example_com_apt`
+    fn default_filename(&self) -> String {
+        let uri = self
+            .uri
+            .split_once("://")
+            .map_or(self.uri.as_str(), |(_, rest)| rest);
+        uri.trim_matches('/')
+            .chars()
+            .map(|c| match c.is_ascii_alphanumeric() || c == '-' {
+                true => c,
+                false => '_',
+            })
+            .collect()
+    }
+}
+
+// The contents of a `.list` file with `source` added or replaced, or
+// removed when `source` is `None`
+fn update_list(
+    contents: &str,
+    repo: &Source,
+    source: Option<&Source>,
+) -> String {
+    let mut lines = vec![];
+    let mut found = false;
+    for line in contents.lines() {
+        match Source::parse(line) {
+            Some(existing) if existing.same_repo(repo) => {
+                if let Some(source) = source.filter(|_| !found) {
+                    lines.push(source.to_line());
+                }
+                found = true;
+            }
+            _ => lines.push(line.to_string()),
+        }
+    }
+    if let Some(source) = source.filter(|_| !found) {
+        lines.push(source.to_line());
+    }
+
+    let mut updated = lines.join("\n");
+    if !updated.is_empty() {
+        updated.push('\n');
+    }
+    updated
+}
+
+// Whether a `.list` file has anything but comments left in it
+fn has_sources(contents: &str) -> bool {
+    contents.lines().any(|line| {
+        let line = line.trim();
+        !line.is_empty() && !line.starts_with('#')
+    })
+}
+
+// Writes `contents` to `path` unless it already holds them. A `None`
+// removes the file. Returns whether anything changed.
+fn sync_file(
+    path: &Path,
+    contents: Option<&[u8]>,
+    check_mode: bool,
+) -> Result<bool> {
+    let current = match fs::read(path) {
+        Ok(current) => Some(current),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e.into()),
+    };
+    if current.as_deref() == contents {
+        return Ok(false);
+    }
+    if !check_mode {
+        match contents {
+            Some(contents) => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(path, contents)?;
+            }
+            None => fs::remove_file(path)?,
+        }
+    }
+    Ok(true)
+}
+
+// The key's contents, and the extension apt expects for its kind
+fn read_key(key: &str) -> Result<(Vec<u8>, &'static str)> {
+    let contents = match key.trim_start().starts_with(ARMORED_KEY) {
+        true => key.as_bytes().to_vec(),
+        false => fs::read(key)
+            .map_err(|e| invalid(format!("failed to read key {key}: {e}")))?,
+    };
+    match contents.starts_with(ARMORED_KEY.as_bytes()) {
+        true => Ok((contents, "asc")),
+        false => Ok((contents, "gpg")),
+    }
+}
+
+fn run(args: &AptRepositoryArgs, check_mode: bool) -> Result<TaskResult> {
+    let mut source = Source::parse(&args.repo).ok_or_else(|| {
+        invalid(format!("`{}` is not a valid source entry", args.repo))
+    })?;
+    let filename = args
+        .filename
+        .clone()
+        .unwrap_or_else(|| source.default_filename());
+    let etc_apt = args.root.join("etc/apt");
+
+    let mut changed = false;
+    let mut result = TaskResult::new(false);
+    let mut signed_by = args.signed_by.clone();
+    if let Some(key) = &args.key {
+        let (contents, extension) = read_key(key)?;
+        let keyring = format!("/etc/apt/keyrings/{filename}.{extension}");
+        let path = etc_apt.join(format!("keyrings/{filename}.{extension}"));
+        let contents = args.present.then_some(contents.as_slice());
+        changed |= sync_file(&path, contents, check_mode)?;
+        // Drop the key when it was replaced by one of the other kind
+        let other = match extension {
+            "asc" => path.with_extension("gpg"),
+            _ => path.with_extension("asc"),
+        };
+        changed |= sync_file(&other, None, check_mode)?;
+        result.set("keyring", Yaml::String(keyring.clone()));
+        signed_by.get_or_insert(keyring);
+    }
+    if let Some(signed_by) = &signed_by {
+        source.set_option("signed-by", signed_by);
+    }
+
+    let sources_file = match args.format {
+        Format::OneLine => {
+            let path = etc_apt.join(format!("sources.list.d/{filename}.list"));
+            let current = match fs::read_to_string(&path) {
+                Ok(current) => current,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    String::new()
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let updated =
+                update_list(&current, &source, args.present.then_some(&source));
+            let contents = has_sources(&updated).then_some(updated.as_bytes());
+            changed |= sync_file(&path, contents, check_mode)?;
+            path
+        }
+        Format::Deb822 => {
+            let path =
+                etc_apt.join(format!("sources.list.d/{filename}.sources"));
+            let stanza = source.to_deb822();
+            let contents = args.present.then_some(stanza.as_bytes());
+            changed |= sync_file(&path, contents, check_mode)?;
+            path
+        }
+    };
+
+    let update_cache = changed && args.update_cache && !check_mode;
+    if update_cache {
+        update_apt_cache(&AptArgs::new(vec![]))?;
+    }
+
+    result.changed = changed;
+    result.msg = match (changed, args.present, check_mode) {
+        (false, _, _) => "Repository is already configured".to_string(),
+        (true, true, false) => format!("Added {}", source.to_line()),
+        (true, true, true) => format!("Would add {}", source.to_line()),
+        (true, false, false) => format!("Removed {}", source.to_line()),
+        (true, false, true) => format!("Would remove {}", source.to_line()),
+    };
+    result.set("repo", Yaml::String(source.to_line()));
+    result.set(
+        "sources_file",
+        Yaml::String(sources_file.display().to_string()),
+    );
+    result.set("cache_updated", Yaml::Boolean(update_cache));
+    Ok(result)
+}
+
+pub struct AptRepository;
+
+impl Module for AptRepository {
+    type Args = AptRepositoryArgs;
+
+    fn parse_args(&self, args: &Yaml) -> Result<Self::Args> {
+        let args = Args::new("apt_repository", args, OPTIONS)?;
+        let mut repo_args =
+            AptRepositoryArgs::new(args.required_string("repo")?);
+        repo_args.present =
+            args.choice("state", &["present", "absent"])?.as_deref()
+                != Some("absent");
+        repo_args.filename = args.string("filename")?;
+        if let Some(filename) = &repo_args.filename {
+            if filename.is_empty() || filename.contains('/') {
+                return Err(invalid(format!(
+                    "`filename` must be a plain file name, got: {filename}"
+                )));
+            }
+        }
+        repo_args.format =
+            match args.choice("format", &["one_line", "deb822"])?.as_deref() {
+                Some("deb822") => Format::Deb822,
+                _ => Format::OneLine,
+            };
+        repo_args.key = args.string("key")?;
+        repo_args.signed_by = args.string("signed_by")?;
+        repo_args.update_cache = args.bool("update_cache")?.unwrap_or(true);
+        if Source::parse(&repo_args.repo).is_none() {
+            return Err(invalid(format!(
+                "`{}` is not a valid source entry",
+                repo_args.repo
+            )));
+        }
+        Ok(repo_args)
+    }
+
+    fn run(&self, args: Self::Args) -> Result<TaskResult> {
+        run(&args, false)
+    }
+
+    fn check(&self, args: Self::Args) -> Result<TaskResult> {
+        run(&args, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const REPO: &str = "deb [arch=amd64] https://example.com/apt/ stable main";
+
+    fn args(tmp: &TempDir) -> AptRepositoryArgs {
+        let mut args = AptRepositoryArgs::new(REPO);
+        args.update_cache = false;
+        args.root = tmp.path().to_path_buf();
+        args
+    }
+
+    fn read(tmp: &TempDir, path: &str) -> String {
+        fs::read_to_string(tmp.path().join(path)).unwrap()
+    }
+
+    #[test]
+    fn repository_source() {
+        let source = Source::parse(REPO).unwrap();
+        assert_eq!(source.options, [("arch".into(), "amd64".into())]);
+        assert_eq!(source.to_line(), REPO);
+        assert_eq!(source.default_filename(), "example_com_apt");
+        assert!(source.same_repo(
+            &Source::parse("deb https://example.com/apt stable main").unwrap()
+        ));
+        assert!(!source.same_repo(
+            &Source::parse("deb-src https://example.com/apt/ stable main")
+                .unwrap()
+        ));
+        assert_eq!(Source::parse("# deb http://example.com stable"), None);
+        assert_eq!(Source::parse("deb [arch=amd64 http://example.com"), None);
+
+        let mut source = source;
+        source.set_option("arch", "amd64,arm64");
+        source.set_option("signed-by", "/etc/apt/keyrings/example.asc");
+        assert_eq!(
+            source.to_deb822(),
+            "Types: deb\nURIs: https://example.com/apt/\nSuites: stable\n\
+             Components: main\nArchitectures: amd64 arm64\n\
+             Signed-By: /etc/apt/keyrings/example.asc\n"
+        );
+    }
+
+    #[test]
+    fn repository_one_line() {
+        let tmp = TempDir::new().unwrap();
+        let list = "etc/apt/sources.list.d/example_com_apt.list";
+        let path = tmp.path().join(list);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, "# Example\ndeb http://other.example.com x main\n")
+            .unwrap();
+
+        let mut args = args(&tmp);
+        assert!(run(&args, true).unwrap().changed);
+        assert!(!read(&tmp, list).contains("example.com/apt"));
+
+        let result = run(&args, false).unwrap();
+        assert!(result.changed);
+        assert_eq!(
+            read(&tmp, list),
+            format!("# Example\ndeb http://other.example.com x main\n{REPO}\n")
+        );
+        assert!(!run(&args, false).unwrap().changed);
+
+        args.signed_by = Some("/usr/share/keyrings/example.gpg".to_string());
+        assert!(run(&args, false).unwrap().changed);
+        assert_eq!(
+            read(&tmp, list).lines().last().unwrap(),
+            "deb [arch=amd64 signed-by=/usr/share/keyrings/example.gpg] \
+             https://example.com/apt/ stable main"
+        );
+
+        args.present = false;
+        assert!(run(&args, false).unwrap().changed);
+        assert_eq!(
+            read(&tmp, list),
+            "# Example\ndeb http://other.example.com x main\n"
+        );
+        assert!(!run(&args, false).unwrap().changed);
+
+        fs::write(&path, format!("{REPO}\n")).unwrap();
+        assert!(run(&args, false).unwrap().changed);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn repository_deb822_and_key() {
+        let tmp = TempDir::new().unwrap();
+        let key = tmp.path().join("example.gpg");
+        fs::write(&key, b"\x99\x01\x0dbinary key").unwrap();
+
+        let mut args = args(&tmp);
+        args.format = Format::Deb822;
+        args.filename = Some("example".to_string());
+        args.key = Some(key.display().to_string());
+        let result = run(&args, false).unwrap();
+        assert!(result.changed);
+        assert_eq!(
+            result.get("keyring"),
+            Some(&Yaml::String("/etc/apt/keyrings/example.gpg".to_string()))
+        );
+        assert_eq!(
+            fs::read(tmp.path().join("etc/apt/keyrings/example.gpg")).unwrap(),
+            b"\x99\x01\x0dbinary key"
+        );
+        let sources = "etc/apt/sources.list.d/example.sources";
+        assert!(read(&tmp, sources)
+            .contains("Signed-By: /etc/apt/keyrings/example.gpg\n"));
+        assert!(!run(&args, false).unwrap().changed);
+
+        let armored = format!("{ARMORED_KEY}\n\nmQINBF...\n");
+        args.key = Some(armored.clone());
+        assert!(run(&args, false).unwrap().changed);
+        assert_eq!(read(&tmp, "etc/apt/keyrings/example.asc"), armored);
+        assert!(!tmp.path().join("etc/apt/keyrings/example.gpg").exists());
+        assert!(read(&tmp, sources).contains("/etc/apt/keyrings/example.asc"));
+
+        args.present = false;
+        assert!(run(&args, true).unwrap().changed);
+        assert!(tmp.path().join(sources).exists());
+        assert!(run(&args, false).unwrap().changed);
+        assert!(!tmp.path().join(sources).exists());
+        assert!(!tmp.path().join("etc/apt/keyrings/example.asc").exists());
+    }
+
+    #[test]
+    fn repository_args() {
+        let load = |source: &str| {
+            yaml_rust::YamlLoader::load_from_str(source)
+                .unwrap()
+                .remove(0)
+        };
+        let args = AptRepository
+            .parse_args(&load(&format!("repo: {REPO}\nformat: deb822")))
+            .unwrap();
+        assert_eq!(args.format, Format::Deb822);
+        assert!(args.present);
+        assert!(args.update_cache);
+
+        assert!(AptRepository.parse_args(&load("repo: nonsense")).is_err());
+        assert!(AptRepository
+            .parse_args(&load(&format!("repo: {REPO}\nfilename: a/b")))
+            .is_err());
+    }
+}
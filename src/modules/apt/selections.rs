@@ -0,0 +1,257 @@
+// Sets dpkg selections, chiefly to hold packages at their current version
+use super::{AptError, Result};
+use crate::modules::args::Args;
+use crate::modules::{self, Module, ModuleError, TaskResult};
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+const OPTIONS: &[&str] = &["name", "selection"];
+
+const SELECTIONS: &[&str] = &["install", "hold", "deinstall", "purge"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct DpkgSelectionsArgs {
+    pub names: Vec<String>,
+    /// One of `install`, `hold`, `deinstall` or `purge`
+    pub selection: String,
+}
+
+// Parses `dpkg --get-selections`, `name[:arch]<tabs>selection` per line
+fn parse_selections(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            Some((words.next()?.to_string(), words.next()?.to_string()))
+        })
+        .collect()
+}
+
+// The selection of `name`, which dpkg may list with its architecture
+fn lookup<'a>(
+    selections: &'a HashMap<String, String>,
+    name: &str,
+) -> Option<(&'a str, &'a str)> {
+    selections
+        .get_key_value(name)
+        .or_else(|| {
+            selections.iter().find(|(listed, _)| {
+                listed.split_once(':').is_some_and(|(base, _)| base == name)
+            })
+        })
+        .map(|(listed, selection)| (listed.as_str(), selection.as_str()))
+}
+
+fn check_output(command: &str, output: Output) -> Result<Output> {
+    match output.status.success() {
+        true => Ok(output),
+        false => Err(AptError::new(format!(
+            "`{command}` failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))),
+    }
+}
+
+fn get_selections(names: &[String]) -> Result<HashMap<String, String>> {
+    let output = Command::new("dpkg")
+        .arg("--get-selections")
+        .args(names)
+        .output()?;
+    let output = check_output("dpkg --get-selections", output)?;
+    Ok(parse_selections(&String::from_utf8(output.stdout)?))
+}
+
+fn set_selections(selections: &[(String, &str)]) -> Result<()> {
+    let mut child = Command::new("dpkg")
+        .arg("--set-selections")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    for (name, selection) in selections {
+        writeln!(stdin, "{name} {selection}")?;
+    }
+    drop(stdin);
+    check_output("dpkg --set-selections", child.wait_with_output()?)?;
+    Ok(())
+}
+
+fn apt_mark(command: &str, names: &[String]) -> Result<()> {
+    let output = Command::new("apt-mark").arg(command).args(names).output()?;
+    check_output(&format!("apt-mark {command}"), output)?;
+    Ok(())
+}
+
+// The packages whose selection has to change, as dpkg lists them, with
+// their current selection
+fn plan(
+    args: &DpkgSelectionsArgs,
+    selections: &HashMap<String, String>,
+) -> Result<Vec<(String, String)>> {
+    let mut changes = vec![];
+    for name in &args.names {
+        match lookup(selections, name) {
+            Some((_, current)) if current == args.selection => {}
+            Some((listed, current)) => {
+                changes.push((listed.to_string(), current.to_string()))
+            }
+            // A package dpkg doesn't know about is as removed as it gets
+            None if matches!(
+                args.selection.as_str(),
+                "deinstall" | "purge"
+            ) => {}
+            None => {
+                return Err(AptError::new(format!(
+                    "Package {name} is not known to dpkg"
+                )))
+            }
+        }
+    }
+    Ok(changes)
+}
+
+fn run(args: &DpkgSelectionsArgs, check_mode: bool) -> Result<TaskResult> {
+    let changes = plan(args, &get_selections(&args.names)?)?;
+    let names: Vec<String> =
+        changes.iter().map(|(name, _)| name.clone()).collect();
+
+    if !check_mode && !changes.is_empty() {
+        // apt-mark keeps apt's own record of holds in step
+        let held = changes.iter().all(|(_, current)| current == "hold");
+        match args.selection.as_str() {
+            "hold" => apt_mark("hold", &names)?,
+            "install" if held => apt_mark("unhold", &names)?,
+            selection => {
+                let selections: Vec<(String, &str)> = names
+                    .iter()
+                    .map(|name| (name.clone(), selection))
+                    .collect();
+                set_selections(&selections)?
+            }
+        }
+    }
+
+    let mut result = TaskResult::new(!changes.is_empty());
+    result.msg = match (changes.is_empty(), check_mode) {
+        (true, _) => format!("Selections are already {}", args.selection),
+        (false, true) => {
+            format!("Would set {} to {}", names.join(", "), args.selection)
+        }
+        (false, false) => {
+            format!("Set {} to {}", names.join(", "), args.selection)
+        }
+    };
+    let mut before = Hash::new();
+    for (name, current) in changes {
+        before.insert(Yaml::String(name), Yaml::String(current));
+    }
+    result.set("before", Yaml::Hash(before));
+    result.set("selection", Yaml::String(args.selection.clone()));
+    Ok(result)
+}
+
+pub struct DpkgSelections;
+
+impl Module for DpkgSelections {
+    type Args = DpkgSelectionsArgs;
+
+    fn parse_args(&self, args: &Yaml) -> modules::Result<Self::Args> {
+        let args = Args::new("dpkg_selections", args, OPTIONS)?;
+        let names = args.list("name")?.unwrap_or_default();
+        if names.is_empty() {
+            return Err(ModuleError::PlainMessage(
+                "dpkg_selections: missing required argument `name`".to_string(),
+            ));
+        }
+        let selection =
+            args.choice("selection", SELECTIONS)?.ok_or_else(|| {
+                ModuleError::PlainMessage(
+                    "dpkg_selections: missing required argument `selection`"
+                        .to_string(),
+                )
+            })?;
+        Ok(DpkgSelectionsArgs { names, selection })
+    }
+
+    fn run(&self, args: Self::Args) -> modules::Result<TaskResult> {
+        Ok(run(&args, false)?)
+    }
+
+    fn check(&self, args: Self::Args) -> modules::Result<TaskResult> {
+        Ok(run(&args, true)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SELECTIONS: &str = "\
+bash\t\t\t\t\t\tinstall
+libc6:amd64\t\t\t\t\tinstall
+linux-image-amd64\t\t\t\thold
+nano\t\t\t\t\t\tdeinstall
+";
+
+    fn args(names: &[&str], selection: &str) -> DpkgSelectionsArgs {
+        DpkgSelectionsArgs {
+            names: names.iter().map(|name| name.to_string()).collect(),
+            selection: selection.to_string(),
+        }
+    }
+
+    #[test]
+    fn selections_parse() {
+        let selections = parse_selections(SELECTIONS);
+        assert_eq!(selections["linux-image-amd64"], "hold");
+        assert_eq!(
+            lookup(&selections, "libc6"),
+            Some(("libc6:amd64", "install"))
+        );
+        assert_eq!(lookup(&selections, "libc"), None);
+    }
+
+    #[test]
+    fn selections_plan() {
+        let selections = parse_selections(SELECTIONS);
+        let owned = |name: &str, selection: &str| {
+            (name.to_string(), selection.to_string())
+        };
+
+        let changes =
+            plan(&args(&["linux-image-amd64", "libc6"], "hold"), &selections);
+        assert_eq!(changes.unwrap(), [owned("libc6:amd64", "install")]);
+
+        let changes = plan(
+            &args(&["linux-image-amd64", "bash"], "install"),
+            &selections,
+        );
+        assert_eq!(changes.unwrap(), [owned("linux-image-amd64", "hold")]);
+
+        let changes = plan(&args(&["nano", "missing"], "purge"), &selections);
+        assert_eq!(changes.unwrap(), [owned("nano", "deinstall")]);
+        assert!(plan(&args(&["missing"], "hold"), &selections).is_err());
+    }
+
+    #[test]
+    fn selections_args() {
+        let load = |source: &str| {
+            yaml_rust::YamlLoader::load_from_str(source)
+                .unwrap()
+                .remove(0)
+        };
+        let parsed = DpkgSelections
+            .parse_args(&load("name: [linux-image-amd64]\nselection: hold"))
+            .unwrap();
+        assert_eq!(parsed, args(&["linux-image-amd64"], "hold"));
+        assert!(DpkgSelections.parse_args(&load("name: bash")).is_err());
+        assert!(DpkgSelections
+            .parse_args(&load("name: bash\nselection: keep"))
+            .is_err());
+    }
+}
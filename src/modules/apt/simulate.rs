@@ -0,0 +1,218 @@
+// Reads what apt-get would do from its `--simulate` output, which lists one
+// action per line:
+//
+//     Inst bash [5.2.15-2+b8] (5.2.15-2+b13 Debian:12.13/stable [amd64])
+//     Conf bash (5.2.15-2+b13 Debian:12.13/stable [amd64])
+//     Remv nano [7.2-1]
+use super::{apt_get, AptArgs, Result};
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    pub name: String,
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+impl Change {
+    pub fn to_yaml(&self) -> Yaml {
+        let mut hash = Hash::new();
+        let version = |version: &Option<String>| {
+            version.clone().map_or(Yaml::Null, Yaml::String)
+        };
+        hash.insert(
+            Yaml::String("name".to_string()),
+            Yaml::String(self.name.clone()),
+        );
+        hash.insert(Yaml::String("from".to_string()), version(&self.from));
+        hash.insert(Yaml::String("to".to_string()), version(&self.to));
+        Yaml::Hash(hash)
+    }
+}
+
+/// The packages a command would install, upgrade or remove
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Simulation {
+    pub installed: Vec<Change>,
+    pub upgraded: Vec<Change>,
+    pub removed: Vec<Change>,
+}
+
+impl Simulation {
+    pub fn parse(stdout: &str) -> Self {
+        let mut simulation = Self::default();
+        for line in stdout.lines() {
+            let Some((action, rest)) = line.split_once(' ') else {
+                continue;
+            };
+            let (name, rest) = rest.split_once(' ').unwrap_or((rest, ""));
+            // The installed version is in brackets, the new one in parens
+            let from = rest
+                .strip_prefix('[')
+                .and_then(|rest| rest.split_once(']'))
+                .map(|(from, _)| from.to_string());
+            let to = rest
+                .split_once('(')
+                .and_then(|(_, to)| to.split_whitespace().next())
+                .map(|to| to.trim_end_matches(')').to_string());
+            let change = Change {
+                name: name.to_string(),
+                from,
+                to,
+            };
+            match action {
+                "Inst" if change.from.is_some() => {
+                    simulation.upgraded.push(change)
+                }
+                "Inst" => simulation.installed.push(change),
+                "Remv" | "Purg" => simulation.removed.push(change),
+                _ => {}
+            }
+        }
+        simulation
+    }
+
+    /// Runs an `apt-get` command with `--simulate`
+    pub fn run(args: &AptArgs, options: &[String]) -> Result<Self> {
+        let options = options.iter().map(String::as_str);
+        let output = apt_get(args, options.chain(["--simulate"]))?;
+        Ok(Self::parse(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    pub fn to_yaml(&self) -> Yaml {
+        let mut hash = Hash::new();
+        for (key, changes) in [
+            ("installed", &self.installed),
+            ("upgraded", &self.upgraded),
+            ("removed", &self.removed),
+        ] {
+            hash.insert(Yaml::String(key.to_string()), yaml_changes(changes));
+        }
+        Yaml::Hash(hash)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.installed.is_empty()
+            && self.upgraded.is_empty()
+            && self.removed.is_empty()
+    }
+}
+
+pub fn yaml_changes(changes: &[Change]) -> Yaml {
+    Yaml::Array(changes.iter().map(Change::to_yaml).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Captured from Debian 12 as a non-root user, trimmed, with a removal
+    // and a new dependency added
+    const DIST_UPGRADE: &str = "\
+NOTE: This is only a simulation!
+      apt-get needs root privileges for real execution.
+      Keep also in mind that locking is deactivated,
+      so don't depend on the relevance to the real current situation!
+Reading package lists...
+Building dependency tree...
+Reading state information...
+Calculating upgrade...
+The following packages will be REMOVED:
+  libfoo1
+The following NEW packages will be installed:
+  libfoo2
+The following packages will be upgraded:
+  bash dpkg
+2 upgraded, 1 newly installed, 1 to remove and 0 not upgraded.
+Remv libfoo1 [1.0-1]
+Inst libfoo2 (2.0-1 Debian:12.13/stable [amd64])
+Inst bash [5.2.15-2+b8] (5.2.15-2+b13 Debian:12.13/stable [amd64])
+Inst dpkg [1.21.22] (1.21.23 Debian:12.13/stable [amd64]) []
+Conf libfoo2 (2.0-1 Debian:12.13/stable [amd64])
+Conf bash (5.2.15-2+b13 Debian:12.13/stable [amd64])
+Conf dpkg (1.21.23 Debian:12.13/stable [amd64])
+";
+
+    // Captured from Debian 12
+    const INSTALL: &str = "\
+Reading package lists...
+Building dependency tree...
+Reading state information...
+The following additional packages will be installed:
+  libtext-charwidth-perl
+Suggested packages:
+  filters cowsay-off
+The following NEW packages will be installed:
+  cowsay libtext-charwidth-perl
+0 upgraded, 2 newly installed, 0 to remove and 97 not upgraded.
+Inst libtext-charwidth-perl (0.04-11 Debian:12.14/oldstable [amd64])
+Inst cowsay (3.03+dfsg2-8 Debian:12.14/oldstable [all])
+Conf libtext-charwidth-perl (0.04-11 Debian:12.14/oldstable [amd64])
+Conf cowsay (3.03+dfsg2-8 Debian:12.14/oldstable [all])
+";
+
+    const PURGE: &str = "\
+Reading package lists...
+Building dependency tree...
+Reading state information...
+The following package was automatically installed and is no longer required:
+  libcurl4
+Use 'apt autoremove' to remove it.
+The following packages will be REMOVED:
+  curl*
+0 upgraded, 0 newly installed, 1 to remove and 97 not upgraded.
+Purg curl [7.88.1-10+deb12u14]
+";
+
+    fn change(name: &str, from: Option<&str>, to: Option<&str>) -> Change {
+        Change {
+            name: name.to_string(),
+            from: from.map(str::to_string),
+            to: to.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn simulate_parse() {
+        let simulation = Simulation::parse(DIST_UPGRADE);
+        assert_eq!(
+            simulation.upgraded,
+            [
+                change("bash", Some("5.2.15-2+b8"), Some("5.2.15-2+b13")),
+                change("dpkg", Some("1.21.22"), Some("1.21.23")),
+            ]
+        );
+        assert_eq!(
+            simulation.installed,
+            [change("libfoo2", None, Some("2.0-1"))]
+        );
+        assert_eq!(
+            simulation.removed,
+            [change("libfoo1", Some("1.0-1"), None)]
+        );
+        assert!(!simulation.is_empty());
+
+        let simulation = Simulation::parse(INSTALL);
+        assert_eq!(
+            simulation.installed,
+            [
+                change("libtext-charwidth-perl", None, Some("0.04-11")),
+                change("cowsay", None, Some("3.03+dfsg2-8")),
+            ]
+        );
+        assert!(simulation.upgraded.is_empty());
+        assert!(simulation.removed.is_empty());
+
+        let simulation = Simulation::parse(PURGE);
+        assert_eq!(
+            simulation.removed,
+            [change("curl", Some("7.88.1-10+deb12u14"), None)]
+        );
+        assert!(simulation.installed.is_empty());
+
+        let nothing = "Reading package lists...\n\
+            0 upgraded, 0 newly installed, 0 to remove and 0 not upgraded.\n";
+        assert!(Simulation::parse(nothing).is_empty());
+    }
+}
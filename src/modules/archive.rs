@@ -1,11 +1,18 @@
+// Tells archive types apart, and creates archives from files
+mod write;
+
 use expanduser::expanduser;
+pub(crate) use write::compress;
+use write::Entry;
+use yaml_rust::Yaml;
 
-use super::{ModuleError, Result};
+use super::args::Args;
+use super::temp::staging_path;
+use super::{Module, ModuleError, Result, TaskResult};
 use std::fmt;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 
 // consists of ArchiveType, magic, and offset of magic
 const MAGIC: &[(ArchiveType, &[u8], usize)] = &[
@@ -58,18 +65,21 @@ pub enum ArchiveType {
 }
 
 impl ArchiveType {
-    fn match_extension(filepath: &Path) -> Result<Self> {
+    pub fn match_extension(filepath: &Path) -> Result<Self> {
         // Since there can be multiple extensions, like
         // .tar.gz, it's easier to match against the full filename,
         // excluding everything before the first '.'
         let extension = filepath
             .file_name()
-            .expect("Filename somehow ended with '..'")
-            .to_str()
-            .expect("Filename contained invalid UTF-8")
-            .split_once(".")
-            .expect("Filename does not have an extension")
-            .1;
+            .and_then(|name| name.to_str())
+            .and_then(|name| name.split_once("."))
+            .map(|(_, extension)| extension)
+            .ok_or_else(|| {
+                ModuleError::PlainMessage(format!(
+                    "{} does not have an archive file extension",
+                    filepath.display()
+                ))
+            })?;
 
         match extension {
             "zip" => Ok(Self::Zip),
@@ -137,194 +147,309 @@ impl fmt::Display for ArchiveType {
     }
 }
 
-// pub fn unarchive()
-
-// fn expand_home(archive: &mut String) -> Result<String, &'static str> {
-//     match env::var("HOME") {
-//         Ok(home) => Ok(archive.replacen("~", home.as_str(), 1)),
-//         Err(_) => Err("HOME environment variable not found"),
-//     }
-// }
-
-// fn unzip(archive: std::path::PathBuf) -> Result<(), &'static str> {
-//     let status = Command::new("unzip").arg(archive).status();
-//     let status = match status {
-//         Ok(ret) => ret,
-//         Err(_) => return Err("Failed to execute `unzip` command"),
-//     };
-//
-//     if !status.success() {
-//         return Err("Unable to run `unzip` on archive");
-//     }
-//
-//     Ok(())
-// }
-// fn unrar(archive: std::path::PathBuf) -> Result<(), &'static str> {
-//     let status = { Command::new("rar").arg("e").arg(archive).status() };
-//     let status = match status {
-//         Ok(ret) => ret,
-//         Err(_) => return Err("Failed to execute `rar` command"),
-//     };
-//
-//     if !status.success() {
-//         return Err("Unable to run `rar` on archive");
-//     }
-//
-//     Ok(())
-// }
-// fn un7z(archive: std::path::PathBuf) -> Result<(), &'static str> {
-//     let status = { Command::new("7z").arg("e").arg(archive).status() };
-//     let status = match status {
-//         Ok(ret) => ret,
-//         Err(_) => return Err("Failed to execute `7z` command"),
-//     };
-//
-//     if !status.success() {
-//         return Err("Unable to run `7z` on archive");
-//     }
-//
-//     Ok(())
-// }
-// fn untar(archive: std::path::PathBuf) -> Result<(), &'static str> {
-//     let status = { Command::new("tar").arg("-xf").arg(archive).status() };
-//     let status = match status {
-//         Ok(ret) => ret,
-//         Err(_) => return Err("Failed to execute `tar` command"),
-//     };
-//
-//     if !status.success() {
-//         return Err("Unable to run `tar` on archive");
-//     }
-//
-//     Ok(())
-// }
-//
-
-// pub fn unarchive(archive: &mut String) -> Result<(), &'static str> {
-//     archive = expand_home(archive)?;
-//     let archive = std::path::PathBuf::from(archive);
-//     println!("{:?}", archive);
-//     let archive = match archive.canonicalize() {
-//         Ok(path) => path,
-//         Err(_) => return Err("archive does not exist"),
-//     };
-//     let archive_type = archive_type(&archive)?;
-//
-//     let current_dir = match std::env::current_dir() {
-//         Ok(path) => path,
-//         Err(_) => return Err("Could not determine the current directory"),
-//     };
-//
-//     let archive_dir = match archive.parent() {
-//         Some(dir) => dir,
-//         None => return Err("Could not determine the parent directory of the archive"),
-//     };
-//
-//     if std::env::set_current_dir(archive_dir).is_err() {
-//         return Err("Unable to change to the archive directory");
-//     };
-//
-//     let result = match archive_type {
-//         ArchiveType::Zip => unzip(archive),
-//         ArchiveType::Rar => unrar(archive),
-//         ArchiveType::SevenZip => un7z(archive),
-//         ArchiveType::Tar
-//         | ArchiveType::TarBzip2
-//         | ArchiveType::TarGzip
-//         | ArchiveType::TarLzip
-//         | ArchiveType::TarLzma
-//         | ArchiveType::TarLzop
-//         | ArchiveType::TarXz
-//         | ArchiveType::TarCompress
-//         | ArchiveType::TarZstd => untar(archive),
-//     };
-//
-//     if result.is_err() {
-//         return Err(result.unwrap_err());
-//     }
-//
-//     match std::env::set_current_dir(current_dir) {
-//         Ok(_) => Ok(()),
-//         Err(_) => Err("Unable to return to the starting directory"),
-//     }
-// }
-
-// fn archive_type(archive: std::path::PathBuf) -> Result<ArchiveType, &'static str> {
-//     // if !valid_filename(archive) {
-//     //     return Err(format!(
-//     //         "Invalid filename for archive: {}",
-//     //         archive.to_string_lossy()
-//     //     ));
-//     // }
-//     let extension = archive.extension();
-//
-//     let stem = match archive.file_stem() {
-//         None => return Err("Invalid archive name"),
-//         Some(basename) => std::path::PathBuf::from(basename),
-//     };
-//
-//     match extension {
-//         None => Err("No extension found for path"),
-//         Some(first_ext) => match first_ext.to_str() {
-//             Some("zip") | Some("zipx") | Some("ZIP") => Ok(ArchiveType::Zip),
-//             Some("rar") => Ok(ArchiveType::Rar),
-//             Some("7z") => Ok(ArchiveType::SevenZip),
-//             Some("tar") => Ok(ArchiveType::Tar),
-//             Some("tb2") | Some("tbz") | Some("tbz2") | Some("tz2") => Ok(ArchiveType::TarBzip2),
-//             Some("bz2") => match stem.to_str() {
-//                 Some("tar") => Ok(ArchiveType::TarBzip2),
-//                 Some(&_) => Err("Invalid archive type for `bzip2` compressed data"),
-//                 None => Err("Could not determine archive type for `bzip2` compressed data"),
-//             },
-//             Some("tgz") | Some("taz") => Ok(ArchiveType::TarGzip),
-//             Some("gz") => match stem.to_str() {
-//                 Some("tar") => Ok(ArchiveType::TarGzip),
-//                 Some(&_) => Err("Invalid archive type for `gzip` compressed data"),
-//                 None => Err("Could not determine archive type for `gzip` compressed data"),
-//             },
-//             Some("lz") => match second_ext.to_str() {
-//                 Some("tar") => Ok(ArchiveType::TarLzip),
-//                 Some(&_) => Err("Invalid archive type for `lzip` compressed data"),
-//                 None => Err("Could not determine archive type for `lzip` compressed data"),
-//             },
-//             Some("tlz") => Ok(ArchiveType::TarLzma),
-//             Some("lzma") => match second_ext.to_str() {
-//                 Some("tar") => Ok(ArchiveType::TarLzma),
-//                 Some(&_) => Err("Invalid archive type for `lzma` compressed data"),
-//                 None => Err("Could not determine archive type for `lzma` compressed data"),
-//             },
-//             Some("lzo") => match second_ext.to_str() {
-//                 Some("tar") => Ok(ArchiveType::TarLzop),
-//                 Some(&_) => Err("Invalid archive type for `lzop` compressed data"),
-//                 None => Err("Could not determine archive type for `lzop` compressed data"),
-//             },
-//             Some("txz") => Ok(ArchiveType::TarXz),
-//             Some("xz") => match second_ext.to_str() {
-//                 Some("tar") => Ok(ArchiveType::TarXz),
-//                 Some(&_) => Err("Invalid archive type for `xz` compressed data"),
-//                 None => Err("Could not determine archive type for `xz` compressed data"),
-//             },
-//             Some("tZ") | Some("taZ") => Ok(ArchiveType::TarCompress),
-//             Some("Z") => match second_ext.to_str() {
-//                 Some("tar") => Ok(ArchiveType::TarCompress),
-//                 Some(&_) => Err("Invalid archive type for `Compress` compressed data"),
-//                 None => Err("Could not determine archive type for `Compress` compressed data"),
-//             },
-//             Some("tzst") => Ok(ArchiveType::TarZstd),
-//             Some("zst") => match second_ext.to_str() {
-//                 Some("tar") => Ok(ArchiveType::TarZstd),
-//                 Some(&_) => Err("Invalid archive type for `zstd` compressed data"),
-//                 None => Err("Could not determine archive type for `zstd` compressed data"),
-//             },
-//             Some(&_) => Err("Unknown archive extension"),
-//             None => Err("Invalid archive string"),
-//         },
-//     }
-// }
+const OPTIONS: &[&str] = &["path", "dest", "format", "exclude_path", "remove"];
+
+/// The archive types which can be written, by their `format`
+const FORMATS: &[(&str, ArchiveType)] = &[
+    ("tar", ArchiveType::Tar),
+    ("tar.gz", ArchiveType::TarGzip),
+    ("tar.bz2", ArchiveType::TarBzip2),
+    ("tar.xz", ArchiveType::TarXz),
+    ("tar.zst", ArchiveType::TarZstd),
+    ("zip", ArchiveType::Zip),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ArchiveArgs {
+    /// Files and directories to archive, as paths or globs
+    pub paths: Vec<String>,
+    pub dest: PathBuf,
+    pub format: ArchiveType,
+    /// Paths or globs left out of the archive, also inside directories
+    pub exclude_paths: Vec<String>,
+    /// Remove the archived files afterwards
+    pub remove: bool,
+}
+
+impl ArchiveArgs {
+    pub fn new(paths: Vec<String>, dest: PathBuf, format: ArchiveType) -> Self {
+        Self {
+            paths,
+            dest,
+            format,
+            exclude_paths: vec![],
+            remove: false,
+        }
+    }
+}
+
+fn format_name(format: &ArchiveType) -> &'static str {
+    FORMATS
+        .iter()
+        .find(|(_, archive_type)| archive_type == format)
+        .map_or("", |(name, _)| name)
+}
+
+fn is_glob(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+fn glob_error(pattern: &str, e: impl fmt::Display) -> ModuleError {
+    ModuleError::PlainMessage(format!("Invalid path pattern {pattern}: {e}"))
+}
+
+// The absolute paths a pattern matches, in order
+fn expand(pattern: &str) -> Result<Vec<PathBuf>> {
+    let pattern = std::path::absolute(expanduser(pattern)?)?;
+    let pattern = pattern.to_string_lossy();
+    let paths = glob::glob(&pattern).map_err(|e| glob_error(&pattern, e))?;
+    paths
+        .map(|path| path.map_err(|e| glob_error(&pattern, e)))
+        .collect()
+}
+
+// The deepest directory holding all of `paths`, which archive member names
+// are relative to
+fn common_root(paths: &[PathBuf]) -> PathBuf {
+    let mut root = paths[0].parent().unwrap_or(Path::new("/")).to_path_buf();
+    for path in &paths[1..] {
+        while !path.starts_with(&root) {
+            if !root.pop() {
+                break;
+            }
+        }
+    }
+    root
+}
+
+/// What goes into an archive
+#[derive(Debug, Default)]
+struct Inputs {
+    entries: Vec<Entry>,
+    /// Patterns which didn't match anything
+    missing: Vec<String>,
+}
+
+// Collects the files and directories to archive, leaving out the archive
+// itself and anything excluded
+fn collect(args: &ArchiveArgs, skip: &[PathBuf]) -> Result<Inputs> {
+    let mut excluded = vec![];
+    for pattern in &args.exclude_paths {
+        let pattern = std::path::absolute(expanduser(pattern)?)?;
+        let pattern = pattern.to_string_lossy();
+        excluded.push(
+            glob::Pattern::new(&pattern)
+                .map_err(|e| glob_error(&pattern, e))?,
+        );
+    }
+    let is_skipped = |path: &Path| {
+        skip.iter().any(|skip| skip == path)
+            || excluded.iter().any(|pattern| pattern.matches_path(path))
+    };
+
+    let mut inputs = Inputs::default();
+    let mut paths = vec![];
+    for pattern in &args.paths {
+        let matched: Vec<PathBuf> = expand(pattern)?
+            .into_iter()
+            .filter(|path| !is_skipped(path))
+            .collect();
+        if matched.is_empty() {
+            inputs.missing.push(pattern.clone());
+        }
+        paths.extend(matched);
+    }
+    if paths.is_empty() {
+        return Ok(inputs);
+    }
+
+    let root = common_root(&paths);
+    let mut pending = paths;
+    let mut found = vec![];
+    while let Some(path) = pending.pop() {
+        if path.symlink_metadata()?.is_dir() {
+            let mut children = vec![];
+            for child in fs::read_dir(&path)? {
+                let child = child?.path();
+                if !is_skipped(&child) {
+                    children.push(child);
+                }
+            }
+            pending.extend(children);
+        }
+        found.push(path);
+    }
+    // Sorted, so the archive doesn't depend on the order of directories
+    found.sort();
+    found.dedup();
+    for path in found {
+        let name = path.strip_prefix(&root).unwrap_or(&path);
+        let name = name.to_string_lossy().into_owned();
+        inputs.entries.push(Entry { path, name });
+    }
+    Ok(inputs)
+}
+
+// Removes archived files, then the directories they leave empty
+fn remove_inputs(entries: &[Entry]) -> Result<()> {
+    let mut dirs = vec![];
+    for entry in entries {
+        match entry.path.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => dirs.push(&entry.path),
+            Ok(_) => fs::remove_file(&entry.path)?,
+            Err(_) => {}
+        }
+    }
+    // Children sort after their parents. Directories still holding
+    // excluded files stay.
+    for dir in dirs.into_iter().rev() {
+        let _ = fs::remove_dir(dir);
+    }
+    Ok(())
+}
+
+fn yaml_paths<'a>(paths: impl Iterator<Item = &'a Path>) -> Yaml {
+    Yaml::Array(
+        paths
+            .map(|path| Yaml::String(path.display().to_string()))
+            .collect(),
+    )
+}
+
+fn run(args: &ArchiveArgs, check_mode: bool) -> Result<TaskResult> {
+    let dest = std::path::absolute(&args.dest)?;
+    let staging = staging_path(&dest);
+    let inputs = collect(args, &[dest.clone(), staging.clone()])?;
+
+    let mut result;
+    if inputs.entries.is_empty() {
+        // The paths were archived and removed by an earlier run
+        if !dest.exists() {
+            return Err(ModuleError::PlainMessage(format!(
+                "None of the paths to archive exist: {}",
+                args.paths.join(", ")
+            )));
+        }
+        result = TaskResult::new(false);
+        result.msg =
+            format!("{} exists, nothing left to archive", dest.display());
+    } else {
+        // Only rebuilt when a member was added, removed or changed in its
+        // size, time or mode, or `dest` can't be read as an archive
+        let records = write::records(&args.format, &inputs.entries)?;
+        let changed = write::stored(&args.format, &dest)
+            .map_or(true, |stored| stored != records);
+        if changed && !check_mode {
+            let written = write::write(&args.format, &staging, &inputs.entries)
+                .and_then(|()| Ok(fs::rename(&staging, &dest)?));
+            if let Err(e) = written {
+                let _ = fs::remove_file(&staging);
+                return Err(e);
+            }
+        }
+        if args.remove && !check_mode {
+            remove_inputs(&inputs.entries)?;
+        }
+
+        result = TaskResult::new(changed || args.remove);
+        result.msg = match (changed, check_mode) {
+            (true, true) => format!("Would write {}", dest.display()),
+            (true, false) => format!("Wrote {}", dest.display()),
+            (false, _) => format!("{} is up to date", dest.display()),
+        };
+        if args.remove {
+            result.msg.push_str(match check_mode {
+                true => ", would remove the archived files",
+                false => ", removed the archived files",
+            });
+        }
+    }
+
+    let archived = inputs
+        .entries
+        .iter()
+        .filter(|entry| !entry.path.is_dir())
+        .map(|entry| entry.path.as_path());
+    result.set("archived", yaml_paths(archived));
+    let missing = inputs.missing.into_iter().map(Yaml::String).collect();
+    result.set("missing", Yaml::Array(missing));
+    result.set("dest", Yaml::String(dest.display().to_string()));
+    result.set(
+        "format",
+        Yaml::String(format_name(&args.format).to_string()),
+    );
+    Ok(result)
+}
+
+pub struct Archive;
+
+impl Module for Archive {
+    type Args = ArchiveArgs;
+
+    fn parse_args(&self, args: &Yaml) -> Result<Self::Args> {
+        let args = Args::new("archive", args, OPTIONS)?;
+        let paths = args.list("path")?.unwrap_or_default();
+        if paths.is_empty() {
+            return Err(ModuleError::PlainMessage(
+                "archive: missing required argument `path`".to_string(),
+            ));
+        }
+        let dest = args.path("dest")?;
+
+        let names: Vec<&str> = FORMATS.iter().map(|(name, _)| *name).collect();
+        let format = match args.choice("format", &names)? {
+            Some(name) => FORMATS
+                .iter()
+                .find(|(format, _)| *format == name)
+                .map(|(_, archive_type)| archive_type.clone()),
+            // Taken from the extension of `dest`, when it has one
+            None => dest
+                .as_deref()
+                .and_then(|dest| ArchiveType::match_extension(dest).ok())
+                .filter(|archive_type| {
+                    FORMATS.iter().any(|(_, format)| format == archive_type)
+                }),
+        }
+        .unwrap_or(ArchiveType::TarGzip);
+
+        let dest =
+            match dest {
+                Some(dest) => dest,
+                // A single path is archived next to itself
+                None if paths.len() == 1 && !is_glob(&paths[0]) => {
+                    let mut dest = expanduser(&paths[0])?.into_os_string();
+                    dest.push(".");
+                    dest.push(format_name(&format));
+                    PathBuf::from(dest)
+                }
+                None => return Err(ModuleError::PlainMessage(
+                    "archive: `dest` is required unless `path` is a single \
+                     path"
+                        .to_string(),
+                )),
+            };
+
+        let mut archive_args = ArchiveArgs::new(paths, dest, format);
+        archive_args.exclude_paths =
+            args.list("exclude_path")?.unwrap_or_default();
+        archive_args.remove = args.bool("remove")?.unwrap_or(false);
+        Ok(archive_args)
+    }
+
+    fn run(&self, args: Self::Args) -> Result<TaskResult> {
+        run(&args, false)
+    }
+
+    fn check(&self, args: Self::Args) -> Result<TaskResult> {
+        run(&args, true)
+    }
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
 
     const TESTTABLE: &[(&str, ArchiveType)] = &[
         ("zip", ArchiveType::Zip),
@@ -382,4 +507,145 @@ mod tests {
             assert_eq!(result, *archive_type);
         }
     }
+
+    fn docs_args(tmp: &Path, format: ArchiveType) -> ArchiveArgs {
+        let path = tmp.join("docs").display().to_string();
+        ArchiveArgs::new(vec![path], tmp.join("docs.archive"), format)
+    }
+
+    fn docs(tmp: &Path) {
+        fs::create_dir_all(tmp.join("docs/notes")).unwrap();
+        fs::write(tmp.join("docs/a.txt"), "text").unwrap();
+        fs::write(tmp.join("docs/notes/b.log"), "log").unwrap();
+    }
+
+    fn archived(result: &TaskResult) -> Vec<String> {
+        let Some(Yaml::Array(archived)) = result.get("archived") else {
+            panic!("archived is missing");
+        };
+        archived
+            .iter()
+            .map(|path| Path::new(path.as_str().unwrap()))
+            .map(|path| path.file_name().unwrap().to_string_lossy().into())
+            .collect()
+    }
+
+    #[test]
+    fn archive_create() {
+        for (_, format) in FORMATS {
+            let tmp = tempfile::tempdir().unwrap();
+            docs(tmp.path());
+            let args = docs_args(tmp.path(), format.clone());
+
+            let result = run(&args, true).unwrap();
+            assert!(result.changed, "{format}");
+            assert!(!args.dest.exists(), "{format}");
+            assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
+
+            let result = run(&args, false).unwrap();
+            assert!(result.changed, "{format}");
+            assert_eq!(archived(&result), ["a.txt", "b.log"], "{format}");
+            assert_eq!(super::archive_magic(&args.dest).unwrap(), *format);
+            assert!(!run(&args, false).unwrap().changed, "{format}");
+
+            fs::write(tmp.path().join("docs/a.txt"), "changed").unwrap();
+            assert!(run(&args, true).unwrap().changed, "{format}");
+            assert!(run(&args, false).unwrap().changed, "{format}");
+            assert!(!run(&args, false).unwrap().changed, "{format}");
+
+            // Same size and time, only the mode differs
+            let a = tmp.path().join("docs/a.txt");
+            fs::set_permissions(&a, fs::Permissions::from_mode(0o600)).unwrap();
+            assert!(run(&args, true).unwrap().changed, "{format}");
+            assert!(run(&args, false).unwrap().changed, "{format}");
+            assert!(!run(&args, false).unwrap().changed, "{format}");
+
+            fs::write(&args.dest, "not an archive").unwrap();
+            assert!(run(&args, false).unwrap().changed, "{format}");
+            assert_eq!(super::archive_magic(&args.dest).unwrap(), *format);
+        }
+    }
+
+    #[test]
+    fn archive_exclude() {
+        let tmp = tempfile::tempdir().unwrap();
+        docs(tmp.path());
+        let mut args = docs_args(tmp.path(), ArchiveType::Tar);
+        args.exclude_paths =
+            vec![tmp.path().join("*/*.log").display().to_string()];
+        let result = run(&args, false).unwrap();
+        assert_eq!(archived(&result), ["a.txt"]);
+
+        let mut archive = tar::Archive::new(File::open(&args.dest).unwrap());
+        let names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(names, ["docs", "docs/a.txt", "docs/notes"]);
+    }
+
+    #[test]
+    fn archive_remove() {
+        let tmp = tempfile::tempdir().unwrap();
+        docs(tmp.path());
+        let mut args = docs_args(tmp.path(), ArchiveType::Zip);
+        args.remove = true;
+        args.paths = vec![tmp.path().join("do*").display().to_string()];
+
+        assert!(run(&args, true).unwrap().changed);
+        assert!(tmp.path().join("docs/a.txt").exists());
+        assert!(run(&args, false).unwrap().changed);
+        assert!(args.dest.exists());
+        assert!(!tmp.path().join("docs").exists());
+
+        // Nothing is left to archive, the archive stays as it was
+        let result = run(&args, false).unwrap();
+        assert!(!result.changed);
+        assert_eq!(super::archive_magic(&args.dest).unwrap(), ArchiveType::Zip);
+        fs::remove_file(&args.dest).unwrap();
+        assert!(run(&args, false).is_err());
+    }
+
+    #[test]
+    fn archive_args() {
+        let load = |source: &str| {
+            yaml_rust::YamlLoader::load_from_str(source)
+                .unwrap()
+                .remove(0)
+        };
+        let parsed = Archive.parse_args(&load("path: /srv/docs")).unwrap();
+        let expected = ArchiveArgs::new(
+            vec!["/srv/docs".to_string()],
+            PathBuf::from("/srv/docs.tar.gz"),
+            ArchiveType::TarGzip,
+        );
+        assert_eq!(parsed, expected);
+
+        let parsed = Archive
+            .parse_args(&load("path: /srv/docs\nformat: zip\nremove: true"))
+            .unwrap();
+        assert_eq!(parsed.dest, PathBuf::from("/srv/docs.zip"));
+        assert!(parsed.remove);
+
+        let parsed = Archive
+            .parse_args(&load(
+                "path: [/srv/a, /srv/b]\ndest: /tmp/out.tar.xz\n\
+                 exclude_path: /srv/a/cache",
+            ))
+            .unwrap();
+        assert_eq!(parsed.format, ArchiveType::TarXz);
+        assert_eq!(parsed.exclude_paths, ["/srv/a/cache"]);
+
+        let parsed = Archive
+            .parse_args(&load("path: /srv/docs\ndest: /tmp/docs.bak"))
+            .unwrap();
+        assert_eq!(parsed.format, ArchiveType::TarGzip);
+
+        assert!(Archive.parse_args(&load("dest: /tmp/out.zip")).is_err());
+        assert!(Archive.parse_args(&load("path: /srv/*.log")).is_err());
+        assert!(Archive
+            .parse_args(&load("path: /srv/docs\nformat: rar"))
+            .is_err());
+    }
 }
@@ -0,0 +1,370 @@
+// Writes archives in-process, and lists what an archive records about its
+// members, which is how the archive module tells whether an archive needs
+// rewriting without writing it again. The output only depends on the files
+// going in, so writing the same files twice gives the same bytes.
+use super::{ArchiveType, FORMATS};
+use crate::modules::{ModuleError, Result};
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// A file, directory or symlink to archive under `name`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub name: String,
+}
+
+/// What an archive records about a member, enough to tell whether the
+/// member would be written differently now
+#[derive(Debug, PartialEq)]
+pub struct Record {
+    pub name: String,
+    pub dir: bool,
+    /// The target of a symlink
+    pub link: Option<String>,
+    /// The size of a file's contents
+    pub size: u64,
+    /// Seconds since the epoch in tarballs, the packed DOS date and time in
+    /// zip files
+    pub mtime: u64,
+    pub mode: u32,
+}
+
+fn packed(time: zip::DateTime) -> u64 {
+    (u64::from(time.datepart()) << 16) | u64::from(time.timepart())
+}
+
+// The date and time of a zip member from seconds since the epoch, as UTC
+fn dos_time(mtime: i64) -> zip::DateTime {
+    // The civil date from days, after Howard Hinnant's algorithm
+    let (days, seconds) = (mtime.div_euclid(86_400), mtime.rem_euclid(86_400));
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524
+        - day_of_era / 146_096)
+        / 365;
+    let day_of_year =
+        day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 {
+        month_index + 3
+    } else {
+        month_index - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    // Zip can't store times before 1980, nor after 2107
+    zip::DateTime::from_date_and_time(
+        year.clamp(1980, 2107) as u16,
+        month as u8,
+        day as u8,
+        (seconds / 3600) as u8,
+        (seconds / 60 % 60) as u8,
+        (seconds % 60) as u8,
+    )
+    .unwrap_or_default()
+}
+
+fn write_tar(writer: &mut dyn Write, entries: &[Entry]) -> io::Result<()> {
+    let mut builder = tar::Builder::new(writer);
+    for entry in entries {
+        let metadata = entry.path.symlink_metadata()?;
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+        if metadata.is_symlink() {
+            let target = fs::read_link(&entry.path)?;
+            builder.append_link(&mut header, &entry.name, target)?;
+        } else if metadata.is_dir() {
+            builder.append_data(&mut header, &entry.name, io::empty())?;
+        } else {
+            let file = File::open(&entry.path)?;
+            builder.append_data(&mut header, &entry.name, file)?;
+        }
+    }
+    builder.finish()
+}
+
+// Has `write` write a tarball into `file`, compressed as `archive_type`
+// says
+fn compressed(
+    archive_type: &ArchiveType,
+    file: File,
+    write: impl FnOnce(&mut dyn Write) -> io::Result<()>,
+) -> io::Result<()> {
+    let file = BufWriter::new(file);
+    let mut writer = match archive_type {
+        ArchiveType::Tar => {
+            let mut writer = file;
+            write(&mut writer)?;
+            writer
+        }
+        ArchiveType::TarGzip => {
+            // Leaves the time out of the gzip header
+            let mut encoder = flate2::GzBuilder::new()
+                .write(file, flate2::Compression::default());
+            write(&mut encoder)?;
+            encoder.finish()?
+        }
+        ArchiveType::TarBzip2 => {
+            let mut encoder = bzip2::write::BzEncoder::new(
+                file,
+                bzip2::Compression::default(),
+            );
+            write(&mut encoder)?;
+            encoder.finish()?
+        }
+        ArchiveType::TarXz => {
+            let mut encoder = xz2::write::XzEncoder::new(file, 6);
+            write(&mut encoder)?;
+            encoder.finish()?
+        }
+        ArchiveType::TarZstd => {
+            let mut encoder = zstd::Encoder::new(file, 0)?;
+            write(&mut encoder)?;
+            encoder.finish()?
+        }
+        _ => {
+            return Err(io::Error::other(format!(
+                "Compressing {archive_type} archives is not supported"
+            )))
+        }
+    };
+    writer.flush()
+}
+
+/// Compresses the tarball read from `tar` into `dest`, as `archive_type`
+/// says
+pub fn compress(
+    archive_type: &ArchiveType,
+    mut tar: impl Read,
+    dest: &Path,
+) -> io::Result<()> {
+    compressed(archive_type, File::create(dest)?, |writer| {
+        io::copy(&mut tar, writer).map(|_| ())
+    })
+}
+
+fn write_zip(file: File, entries: &[Entry]) -> zip::result::ZipResult<()> {
+    let mut writer = zip::ZipWriter::new(BufWriter::new(file));
+    for entry in entries {
+        let metadata = entry.path.symlink_metadata()?;
+        let options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(dos_time(metadata.mtime()))
+            .unix_permissions(metadata.permissions().mode() & 0o7777);
+        if metadata.is_symlink() {
+            let target = fs::read_link(&entry.path)?;
+            let target = target.to_string_lossy();
+            writer.add_symlink(&entry.name, target, options)?;
+        } else if metadata.is_dir() {
+            writer.add_directory(&entry.name, options)?;
+        } else {
+            writer.start_file(&entry.name, options)?;
+            io::copy(&mut File::open(&entry.path)?, &mut writer)?;
+        }
+    }
+    writer.finish()?.flush()?;
+    Ok(())
+}
+
+/// The records an archive of `entries` would hold
+pub fn records(
+    archive_type: &ArchiveType,
+    entries: &[Entry],
+) -> io::Result<Vec<Record>> {
+    let mut records = vec![];
+    for entry in entries {
+        let metadata = entry.path.symlink_metadata()?;
+        let link = match metadata.is_symlink() {
+            true => {
+                Some(fs::read_link(&entry.path)?.to_string_lossy().into_owned())
+            }
+            false => None,
+        };
+        records.push(Record {
+            name: entry.name.clone(),
+            dir: metadata.is_dir(),
+            link,
+            size: if metadata.is_file() {
+                metadata.len()
+            } else {
+                0
+            },
+            mtime: match archive_type {
+                ArchiveType::Zip => packed(dos_time(metadata.mtime())),
+                // As the tar crate stores it
+                _ => metadata.mtime() as u64,
+            },
+            mode: metadata.mode() & 0o7777,
+        });
+    }
+    Ok(records)
+}
+
+fn tar_records(reader: impl Read) -> io::Result<Vec<Record>> {
+    let mut archive = tar::Archive::new(reader);
+    let mut records = vec![];
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let header = entry.header();
+        let kind = header.entry_type();
+        let link = match kind.is_symlink() {
+            true => Some(
+                String::from_utf8_lossy(
+                    &entry.link_name_bytes().unwrap_or_default(),
+                )
+                .into_owned(),
+            ),
+            false => None,
+        };
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        records.push(Record {
+            name: name.trim_end_matches('/').to_string(),
+            dir: kind.is_dir(),
+            link,
+            size: if kind.is_file() { entry.size() } else { 0 },
+            mtime: header.mtime()?,
+            mode: header.mode()? & 0o7777,
+        });
+    }
+    Ok(records)
+}
+
+fn zip_records(file: File) -> zip::result::ZipResult<Vec<Record>> {
+    let mut archive = zip::ZipArchive::new(BufReader::new(file))?;
+    let mut records = vec![];
+    for index in 0..archive.len() {
+        let mut member = archive.by_index(index)?;
+        // A symlink's target is stored as its contents
+        let link = match member.is_symlink() {
+            true => {
+                let mut target = String::new();
+                member.read_to_string(&mut target)?;
+                Some(target)
+            }
+            false => None,
+        };
+        let dir = member.is_dir();
+        records.push(Record {
+            name: member.name().trim_end_matches('/').to_string(),
+            dir,
+            size: if dir || link.is_some() {
+                0
+            } else {
+                member.size()
+            },
+            link,
+            mtime: member.last_modified().map_or(0, packed),
+            mode: member.unix_mode().unwrap_or_default() & 0o7777,
+        });
+    }
+    Ok(records)
+}
+
+/// The records of the archive at `dest`
+pub fn stored(
+    archive_type: &ArchiveType,
+    dest: &Path,
+) -> io::Result<Vec<Record>> {
+    let file = File::open(dest)?;
+    match archive_type {
+        ArchiveType::Zip => {
+            zip_records(file).map_err(|e| io::Error::other(e.to_string()))
+        }
+        ArchiveType::TarGzip => {
+            tar_records(flate2::read::MultiGzDecoder::new(BufReader::new(file)))
+        }
+        ArchiveType::TarBzip2 => {
+            tar_records(bzip2::read::MultiBzDecoder::new(BufReader::new(file)))
+        }
+        ArchiveType::TarXz => tar_records(
+            xz2::read::XzDecoder::new_multi_decoder(BufReader::new(file)),
+        ),
+        ArchiveType::TarZstd => tar_records(zstd::Decoder::new(file)?),
+        _ => tar_records(BufReader::new(file)),
+    }
+}
+
+/// Writes `entries`, in order, into an archive at `dest`
+pub fn write(
+    archive_type: &ArchiveType,
+    dest: &Path,
+    entries: &[Entry],
+) -> Result<()> {
+    if !FORMATS.iter().any(|(_, writable)| writable == archive_type) {
+        return Err(ModuleError::PlainMessage(format!(
+            "Writing {archive_type} archives is not supported"
+        )));
+    }
+    let file = File::create(dest)?;
+    let written = match archive_type {
+        ArchiveType::Zip => write_zip(file, entries)
+            .map_err(|e| io::Error::other(e.to_string())),
+        _ => {
+            compressed(archive_type, file, |writer| write_tar(writer, entries))
+        }
+    };
+    written.map_err(|e| {
+        ModuleError::PlainMessage(format!(
+            "Failed to write {}: {e}",
+            dest.display()
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn write_dos_time() {
+        let time = |year, month, day, hour, minute, second| {
+            zip::DateTime::from_date_and_time(
+                year, month, day, hour, minute, second,
+            )
+            .unwrap()
+        };
+        assert_eq!(dos_time(1_720_884_300), time(2024, 7, 13, 15, 25, 0));
+        assert_eq!(dos_time(951_868_798), time(2000, 2, 29, 23, 59, 58));
+        assert_eq!(dos_time(0), time(1980, 1, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn write_deterministic() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().join("docs");
+        fs::create_dir(&dir).unwrap();
+        fs::write(dir.join("a.txt"), "text").unwrap();
+        std::os::unix::fs::symlink("a.txt", dir.join("link")).unwrap();
+        let entries: Vec<Entry> = ["docs", "docs/a.txt", "docs/link"]
+            .iter()
+            .map(|name| Entry {
+                path: tmp.path().join(name),
+                name: name.to_string(),
+            })
+            .collect();
+
+        for archive_type in [
+            ArchiveType::Tar,
+            ArchiveType::TarGzip,
+            ArchiveType::TarBzip2,
+            ArchiveType::TarXz,
+            ArchiveType::TarZstd,
+            ArchiveType::Zip,
+        ] {
+            let first = tmp.path().join("first");
+            let second = tmp.path().join("second");
+            write(&archive_type, &first, &entries).unwrap();
+            write(&archive_type, &second, &entries).unwrap();
+            let first = fs::read(&first).unwrap();
+            assert_eq!(first, fs::read(&second).unwrap(), "{archive_type}");
+        }
+
+        let dest = tmp.path().join("archive.rar");
+        assert!(write(&ArchiveType::Rar, &dest, &entries).is_err());
+    }
+}
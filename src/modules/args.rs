@@ -0,0 +1,211 @@
+// Helpers for reading the arguments a task passes to a module
+use super::{ModuleError, Result};
+use expanduser::expanduser;
+use std::path::PathBuf;
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+/// A module's arguments, checked against the options the module accepts
+pub struct Args<'a> {
+    module: &'a str,
+    hash: Option<&'a Hash>,
+}
+
+impl<'a> Args<'a> {
+    /// Fails if `args` holds any option not listed in `supported`
+    pub fn new(
+        module: &'a str,
+        args: &'a Yaml,
+        supported: &[&str],
+    ) -> Result<Self> {
+        let hash = match args {
+            Yaml::Hash(hash) => Some(hash),
+            Yaml::Null => None,
+            _ => {
+                return Err(ModuleError::PlainMessage(format!(
+                    "{module}: arguments must be a mapping"
+                )))
+            }
+        };
+
+        let mut unsupported = Vec::new();
+        for key in hash.iter().flat_map(|hash| hash.keys()) {
+            match key.as_str() {
+                Some(key) if supported.contains(&key) => {}
+                Some(key) => unsupported.push(key.to_string()),
+                None => unsupported.push(format!("{key:?}")),
+            }
+        }
+        if !unsupported.is_empty() {
+            return Err(ModuleError::PlainMessage(format!(
+                "{module}: Unsupported parameters: {}. Supported parameters \
+                 include: {}",
+                unsupported.join(", "),
+                supported.join(", ")
+            )));
+        }
+
+        Ok(Self { module, hash })
+    }
+
+    fn get(&self, key: &str) -> Option<&'a Yaml> {
+        let value = self.hash?.get(&Yaml::String(key.to_string()))?;
+        match value {
+            Yaml::Null => None,
+            value => Some(value),
+        }
+    }
+
+    fn invalid(&self, key: &str, expected: &str) -> ModuleError {
+        ModuleError::PlainMessage(format!(
+            "{}: `{key}` must be {expected}",
+            self.module
+        ))
+    }
+
+    fn missing(&self, key: &str) -> ModuleError {
+        ModuleError::PlainMessage(format!(
+            "{}: missing required argument `{key}`",
+            self.module
+        ))
+    }
+
+    pub fn string(&self, key: &str) -> Result<Option<String>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Yaml::String(s)) => Ok(Some(s.clone())),
+            Some(Yaml::Integer(i)) => Ok(Some(i.to_string())),
+            Some(Yaml::Real(r)) => Ok(Some(r.clone())),
+            Some(Yaml::Boolean(b)) => Ok(Some(b.to_string())),
+            Some(_) => Err(self.invalid(key, "a string")),
+        }
+    }
+
+    pub fn required_string(&self, key: &str) -> Result<String> {
+        self.string(key)?.ok_or_else(|| self.missing(key))
+    }
+
+    /// A path, with `~` expanded to the home directory
+    pub fn path(&self, key: &str) -> Result<Option<PathBuf>> {
+        match self.string(key)? {
+            Some(path) => Ok(Some(expanduser(path)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn required_path(&self, key: &str) -> Result<PathBuf> {
+        self.path(key)?.ok_or_else(|| self.missing(key))
+    }
+
+    /// Accepts YAML booleans as well as the `yes`/`no` spellings
+    pub fn bool(&self, key: &str) -> Result<Option<bool>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Yaml::Boolean(b)) => Ok(Some(*b)),
+            Some(Yaml::String(s)) => match s.to_lowercase().as_str() {
+                "yes" | "on" | "true" | "y" => Ok(Some(true)),
+                "no" | "off" | "false" | "n" => Ok(Some(false)),
+                _ => Err(self.invalid(key, "a boolean")),
+            },
+            Some(Yaml::Integer(1)) => Ok(Some(true)),
+            Some(Yaml::Integer(0)) => Ok(Some(false)),
+            Some(_) => Err(self.invalid(key, "a boolean")),
+        }
+    }
+
+    pub fn int(&self, key: &str) -> Result<Option<i64>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Yaml::Integer(i)) => Ok(Some(*i)),
+            Some(Yaml::String(s)) => s
+                .parse()
+                .map(Some)
+                .map_err(|_| self.invalid(key, "an integer")),
+            Some(_) => Err(self.invalid(key, "an integer")),
+        }
+    }
+
+    /// A list of strings, given either as a YAML list or a comma-separated
+    /// string
+    pub fn list(&self, key: &str) -> Result<Option<Vec<String>>> {
+        match self.get(key) {
+            None => Ok(None),
+            Some(Yaml::Array(items)) => items
+                .iter()
+                .map(|item| match item {
+                    Yaml::String(s) => Ok(s.clone()),
+                    Yaml::Integer(i) => Ok(i.to_string()),
+                    Yaml::Real(r) => Ok(r.clone()),
+                    _ => Err(self.invalid(key, "a list of strings")),
+                })
+                .collect::<Result<Vec<_>>>()
+                .map(Some),
+            Some(_) => Ok(self.string(key)?.map(|s| {
+                s.split(',')
+                    .map(|item| item.trim().to_string())
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            })),
+        }
+    }
+
+    /// A string which must be one of `choices`
+    pub fn choice(
+        &self,
+        key: &str,
+        choices: &[&str],
+    ) -> Result<Option<String>> {
+        match self.string(key)? {
+            Some(value) if !choices.contains(&value.as_str()) => Err(self
+                .invalid(
+                    key,
+                    &format!("one of: {}, got: {value}", choices.join(", ")),
+                )),
+            value => Ok(value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use yaml_rust::YamlLoader;
+
+    fn load(source: &str) -> Yaml {
+        YamlLoader::load_from_str(source).unwrap().remove(0)
+    }
+
+    #[test]
+    fn args_values() {
+        let yaml = load(
+            "name: [a, b]\nlist: a, b,\nflag: yes\ncount: '3'\nstate: absent",
+        );
+        let args = Args::new(
+            "test",
+            &yaml,
+            &["name", "list", "flag", "count", "state", "missing"],
+        )
+        .unwrap();
+
+        assert_eq!(args.list("name").unwrap().unwrap(), vec!["a", "b"]);
+        assert_eq!(args.list("list").unwrap().unwrap(), vec!["a", "b"]);
+        assert_eq!(args.bool("flag").unwrap(), Some(true));
+        assert_eq!(args.int("count").unwrap(), Some(3));
+        assert_eq!(args.string("missing").unwrap(), None);
+        assert!(args.required_string("missing").is_err());
+        assert!(args.bool("state").is_err());
+        assert!(args.choice("state", &["present"]).is_err());
+        assert_eq!(
+            args.choice("state", &["present", "absent"]).unwrap(),
+            Some("absent".to_string())
+        );
+    }
+
+    #[test]
+    fn args_unsupported() {
+        let yaml = load("name: a\nbogus: b");
+        assert!(Args::new("test", &yaml, &["name"]).is_err());
+        assert!(Args::new("test", &Yaml::Null, &["name"]).is_ok());
+        assert!(Args::new("test", &load("[a]"), &["name"]).is_err());
+    }
+}
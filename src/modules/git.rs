@@ -1,26 +1,1370 @@
+// Clones git repositories and keeps checkouts at a given version
+mod export;
+
+use super::args::Args;
+use super::{Module, ModuleError, Result, TaskResult};
+use yaml_rust::Yaml;
+
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
-pub fn clone_repo() {
-    let mut status = Command::new("git")
-        .arg("clone")
-        .arg("-q")
-        .arg("https://github.com/SickMcNugget/Waste_Detection_New.git")
-        .status()
-        .expect("failed to execute git clone");
-
-    if status.success() {
-        println!("Successfully cloned to Waste_Detection_New");
-        status = Command::new("rm")
-            .arg("-rf")
-            .arg("Waste_Detection_New")
-            .status()
-            .expect("Failed to remove cloned git repo");
-        if status.success() {
-            println!("Successfully deleted cloned git repo")
-        } else {
-            println!("Failed to delete cloned git repo")
+const OPTIONS: &[&str] = &[
+    "repo",
+    "dest",
+    "version",
+    "depth",
+    "recursive",
+    "update",
+    "force",
+    "single_branch",
+    "verify_commit",
+    "gpg_home",
+    "allowed_signers",
+    "export",
+    "key_file",
+    "ssh_opts",
+    "accept_hostkey",
+    "accept_newhostkey",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GitArgs {
+    pub repo: String,
+    pub dest: PathBuf,
+    /// A branch, tag or commit SHA. `HEAD` follows the remote's default
+    /// branch
+    pub version: String,
+    /// Truncate history to this many commits
+    pub depth: Option<u32>,
+    /// Initialise and update submodules, recursively
+    pub recursive: bool,
+    /// When false an existing checkout is left as it is
+    pub update: bool,
+    /// Discard local modifications to tracked files
+    pub force: bool,
+    /// Only clone the history of `version`
+    pub single_branch: bool,
+    /// Refuse to check out `version` unless it carries a trusted signature
+    pub verify_commit: bool,
+    /// GnuPG home directory holding the keyring to verify against
+    pub gpg_home: Option<PathBuf>,
+    /// `allowed_signers` file to verify SSH signatures against
+    pub allowed_signers: Option<PathBuf>,
+    /// Write the files of `version` to `dest` without a `.git` directory,
+    /// or into an archive when `dest` has an archive extension. Submodules
+    /// are not included.
+    pub export: bool,
+    /// Private key to authenticate to SSH remotes with
+    pub key_file: Option<PathBuf>,
+    /// Extra options passed to `ssh`, e.g. `-o Port=2222`
+    pub ssh_opts: Option<String>,
+    /// Accept any host key, even one which changed
+    pub accept_hostkey: bool,
+    /// Accept host keys of hosts not yet in `known_hosts`
+    pub accept_newhostkey: bool,
+    /// Extra environment for the git commands which talk to the remote
+    pub env: Vec<(String, OsString)>,
+}
+
+impl GitArgs {
+    pub fn new(repo: impl Into<String>, dest: impl Into<PathBuf>) -> Self {
+        Self {
+            repo: repo.into(),
+            dest: dest.into(),
+            version: "HEAD".to_string(),
+            depth: None,
+            recursive: true,
+            update: true,
+            force: false,
+            single_branch: false,
+            verify_commit: false,
+            gpg_home: None,
+            allowed_signers: None,
+            export: false,
+            key_file: None,
+            ssh_opts: None,
+            accept_hostkey: false,
+            accept_newhostkey: false,
+            env: vec![],
         }
+    }
+}
+
+// What `version` turned out to be once the remote had been fetched
+#[derive(Debug, Clone, PartialEq)]
+enum Revision {
+    Branch(String, String),
+    Tag(String, String),
+    Detached(String),
+}
+
+impl Revision {
+    fn sha(&self) -> &str {
+        match self {
+            Self::Branch(_, sha) | Self::Tag(_, sha) | Self::Detached(sha) => {
+                sha
+            }
+        }
+    }
+}
+
+fn git<I, S>(dir: Option<&Path>, args: I) -> Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    Ok(git_untrimmed(dir, args)?.trim().to_string())
+}
+
+// For output where leading whitespace is significant
+fn git_untrimmed<I, S>(dir: Option<&Path>, args: I) -> Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    execute(command(dir), args)
+}
+
+// Runs git for anything which talks to the remote, so that it can use the
+// credentials in `git_args`
+fn remote_git<I, S>(
+    git_args: &GitArgs,
+    dir: Option<&Path>,
+    args: I,
+) -> Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let mut command = command(dir);
+    command.envs(git_args.env.iter().map(|(key, value)| (key, value)));
+    if let Some(ssh) = ssh_command(git_args) {
+        command.env("GIT_SSH_COMMAND", ssh);
+    }
+    Ok(execute(command, args)?.trim().to_string())
+}
+
+fn command(dir: Option<&Path>) -> Command {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+        command.arg("-C").arg(dir);
+    }
+    command
+}
+
+fn execute<I, S>(mut command: Command, args: I) -> Result<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let args: Vec<S> = args.into_iter().collect();
+    let output = command.args(&args).output().map_err(|e| {
+        ModuleError::PlainMessage(format!("Failed to execute git: {e}"))
+    })?;
+
+    if !output.status.success() {
+        let args: Vec<_> = args
+            .iter()
+            .map(|arg| arg.as_ref().to_string_lossy())
+            .collect();
+        return Err(ModuleError::PlainMessage(format!(
+            "`git {}` failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// Quotes `arg` for the shell git runs `GIT_SSH_COMMAND` with
+fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// The `GIT_SSH_COMMAND` to use, `None` if the defaults will do
+fn ssh_command(args: &GitArgs) -> Option<String> {
+    if args.key_file.is_none()
+        && args.ssh_opts.is_none()
+        && !args.accept_hostkey
+        && !args.accept_newhostkey
+    {
+        return None;
+    }
+
+    let mut command = String::from("ssh");
+    if let Some(key_file) = &args.key_file {
+        command.push_str(" -i ");
+        command.push_str(&shell_quote(&key_file.to_string_lossy()));
+        // Don't let keys from an agent get tried first
+        command.push_str(" -o IdentitiesOnly=yes");
+    }
+    if args.accept_hostkey {
+        command.push_str(" -o StrictHostKeyChecking=no");
+    } else if args.accept_newhostkey {
+        command.push_str(" -o StrictHostKeyChecking=accept-new");
+    }
+    if let Some(ssh_opts) = &args.ssh_opts {
+        command.push(' ');
+        command.push_str(ssh_opts);
+    }
+    Some(command)
+}
+
+// `None` when `rev` does not name a commit
+fn rev_parse(dest: &Path, rev: &str) -> Option<String> {
+    git(
+        Some(dest),
+        [
+            "rev-parse",
+            "--quiet",
+            "--verify",
+            &format!("{rev}^{{commit}}"),
+        ],
+    )
+    .ok()
+}
+
+fn resolve_version(args: &GitArgs, version: &str) -> Result<Revision> {
+    let dest = args.dest.as_path();
+    if version == "HEAD" {
+        let head =
+            git(Some(dest), ["symbolic-ref", "refs/remotes/origin/HEAD"]).ok();
+        return match head
+            .as_deref()
+            .and_then(|head| head.strip_prefix("refs/remotes/origin/"))
+        {
+            Some(branch) => resolve_version(args, branch),
+            None => rev_parse(dest, "HEAD").map(Revision::Detached).ok_or_else(
+                || ModuleError::PlainMessage("Repository has no HEAD".into()),
+            ),
+        };
+    }
+
+    if let Some(sha) =
+        rev_parse(dest, &format!("refs/remotes/origin/{version}"))
+    {
+        return Ok(Revision::Branch(version.to_string(), sha));
+    }
+    if let Some(sha) = rev_parse(dest, &format!("refs/tags/{version}")) {
+        return Ok(Revision::Tag(version.to_string(), sha));
+    }
+    if let Some(sha) = rev_parse(dest, version) {
+        return Ok(Revision::Detached(sha));
+    }
+
+    // Commits which aren't on a branch or tag have to be asked for by name
+    if remote_git(
+        args,
+        Some(dest),
+        ["fetch", "--quiet", "--", "origin", version],
+    )
+    .is_ok()
+    {
+        if let Some(sha) = rev_parse(dest, "FETCH_HEAD") {
+            return Ok(Revision::Detached(sha));
+        }
+    }
+
+    Err(ModuleError::PlainMessage(format!(
+        "Failed to find version `{version}` in the repository"
+    )))
+}
+
+fn checkout(dest: &Path, revision: &Revision, force: bool) -> Result<()> {
+    let mut command = vec!["checkout", "--quiet"];
+    if force {
+        command.push("--force");
+    }
+    let upstream;
+    match revision {
+        Revision::Branch(branch, _) => {
+            upstream = format!("origin/{branch}");
+            command.extend(["-B", branch, "--track", &upstream]);
+        }
+        Revision::Tag(_, sha) | Revision::Detached(sha) => {
+            command.extend(["--detach", sha])
+        }
+    }
+    git(Some(dest), command)?;
+    Ok(())
+}
+
+// Whether `version` could be passed to `git clone --branch`
+fn is_named_ref(version: &str) -> bool {
+    version != "HEAD"
+        && !(version.len() >= 7
+            && version.chars().all(|c| c.is_ascii_hexdigit()))
+}
+
+fn clone(args: &GitArgs) -> Result<()> {
+    if let Some(parent) = args.dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    // Checking out is left to `checkout`, after the version has been
+    // resolved and verified
+    let mut command: Vec<String> = ["clone", "--quiet", "--no-checkout"]
+        .map(String::from)
+        .into();
+    if let Some(depth) = args.depth {
+        command.push(format!("--depth={depth}"));
+    }
+    if args.single_branch {
+        command.push("--single-branch".into());
+    } else if args.depth.is_some() {
+        // --depth implies --single-branch, but later runs may ask for a
+        // different version
+        command.push("--no-single-branch".into());
+    }
+    if (args.depth.is_some() || args.single_branch)
+        && is_named_ref(&args.version)
+    {
+        command.push(format!("--branch={}", args.version));
+    }
+    command.push("--".into());
+    command.push(args.repo.clone());
+    command.push(args.dest.to_string_lossy().into_owned());
+
+    remote_git(args, None, command)?;
+    Ok(())
+}
+
+fn update_remote(args: &GitArgs) -> Result<bool> {
+    let url = git(Some(&args.dest), ["config", "--get", "remote.origin.url"])
+        .unwrap_or_default();
+    if url == args.repo {
+        return Ok(false);
+    }
+    if url.is_empty() {
+        git(Some(&args.dest), ["remote", "add", "origin", &args.repo])?;
     } else {
-        println!("Failed to clone to Waste_Detection_New {status}");
+        git(
+            Some(&args.dest),
+            ["remote", "set-url", "origin", &args.repo],
+        )?;
+    }
+    Ok(true)
+}
+
+fn fetch(args: &GitArgs) -> Result<()> {
+    let mut command: Vec<String> =
+        ["fetch", "--quiet", "--tags", "--force", "--prune"]
+            .map(String::from)
+            .into();
+    if let Some(depth) = args.depth {
+        command.push(format!("--depth={depth}"));
+    }
+    command.push("origin".into());
+    remote_git(args, Some(&args.dest), command)?;
+    Ok(())
+}
+
+// One line per submodule with its checked out commit, empty without
+// submodules
+fn submodule_status(dest: &Path) -> String {
+    git(Some(dest), ["submodule", "status", "--recursive"]).unwrap_or_default()
+}
+
+fn update_submodules(args: &GitArgs) -> Result<()> {
+    if !args.dest.join(".gitmodules").exists() {
+        return Ok(());
+    }
+    let mut command: Vec<String> =
+        ["submodule", "update", "--init"].map(String::from).into();
+    if let Some(depth) = args.depth {
+        command.push(format!("--depth={depth}"));
+    }
+    command.push("--recursive".into());
+    remote_git(args, Some(&args.dest), command)?;
+    Ok(())
+}
+
+/// Checks the signature on a tag, or else on the commit, that `revision`
+/// refers to, returning who signed it. Only signatures trusted by the keyring
+/// in `gpg_home` or listed in `allowed_signers` are accepted.
+fn verify(args: &GitArgs, dir: &Path, revision: &Revision) -> Result<String> {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(dir);
+    command.args(["-c", "gpg.minTrustLevel=fully"]);
+    if let Some(allowed_signers) = &args.allowed_signers {
+        let mut config = OsString::from("gpg.ssh.allowedSignersFile=");
+        config.push(allowed_signers);
+        command.arg("-c").arg(config);
+    }
+    if let Some(gpg_home) = &args.gpg_home {
+        command.env("GNUPGHOME", gpg_home);
+    }
+    match revision {
+        Revision::Tag(tag, _) => command.args(["verify-tag", "--raw", tag]),
+        _ => command.args(["verify-commit", "--raw", revision.sha()]),
+    };
+
+    let output = command.output().map_err(|e| {
+        ModuleError::PlainMessage(format!("Failed to execute git: {e}"))
+    })?;
+    // Both gpg and ssh-keygen report on stderr
+    let report = String::from_utf8_lossy(&output.stderr);
+    let what = match revision {
+        Revision::Tag(tag, _) => format!("tag {tag}"),
+        _ => format!("commit {}", revision.sha()),
+    };
+    if !output.status.success() {
+        let reason = match report.trim() {
+            "" => "no signature found",
+            report => report,
+        };
+        return Err(ModuleError::PlainMessage(format!(
+            "Failed to verify the signature of {what}: {reason}"
+        )));
+    }
+
+    signer(&report).ok_or_else(|| {
+        ModuleError::PlainMessage(format!(
+            "Unable to determine who signed {what}: {}",
+            report.trim()
+        ))
+    })
+}
+
+// Pulls the signer out of `gpg --status-fd` or `ssh-keygen -Y verify` output
+fn signer(report: &str) -> Option<String> {
+    report.lines().find_map(|line| {
+        if let Some(goodsig) = line.strip_prefix("[GNUPG:] GOODSIG ") {
+            // GOODSIG <long keyid> <user id>
+            return goodsig.split_once(' ').map(|(_, uid)| uid.to_string());
+        }
+        let principal = line.split_once(" signature for ")?.1;
+        principal.split_once(" with ").map(|(p, _)| p.to_string())
+    })
+}
+
+// Whether tracked files differ from HEAD
+fn is_modified(dest: &Path) -> Result<bool> {
+    let status = git(
+        Some(dest),
+        ["status", "--porcelain", "--untracked-files=no"],
+    )?;
+    Ok(!status.is_empty())
+}
+
+/// Everything in the checkout at `dest` which would be lost by moving it to
+/// `target`: modified and untracked files, and commits which are on neither a
+/// remote branch, a tag nor `target` itself.
+fn local_modifications(
+    dest: &Path,
+    target: Option<&str>,
+) -> Result<Vec<String>> {
+    let mut modifications = Vec::new();
+
+    let status = git_untrimmed(
+        Some(dest),
+        ["status", "--porcelain=v1", "-z", "--untracked-files=all"],
+    )?;
+    // `XY path`, NUL terminated, and renames and copies followed by the
+    // path they came from
+    let mut entries = status.split('\0');
+    while let Some(entry) = entries.next() {
+        let Some((code, path)) = entry.split_at_checked(3) else {
+            continue;
+        };
+        let kind = match code.trim() {
+            code if code.contains(['R', 'C']) => {
+                let kind = match code.contains('R') {
+                    true => "renamed",
+                    false => "copied",
+                };
+                let from = entries.next().unwrap_or_default();
+                modifications.push(format!("{kind}: {from} -> {path}"));
+                continue;
+            }
+            "??" => "untracked",
+            code if code.contains('D') => "deleted",
+            code if code.contains('A') => "added",
+            _ => "modified",
+        };
+        modifications.push(format!("{kind}: {path}"));
+    }
+
+    let mut rev_list = vec![
+        "log",
+        "--format=%h %s",
+        "HEAD",
+        "--not",
+        "--remotes",
+        "--tags",
+    ];
+    rev_list.extend(target);
+    // A repository without commits has nothing to lose here
+    if let Ok(commits) = git(Some(dest), rev_list) {
+        modifications.extend(
+            commits
+                .lines()
+                .map(|commit| format!("local commit: {commit}")),
+        );
+    }
+
+    Ok(modifications)
+}
+
+fn ensure_unmodified(args: &GitArgs, target: Option<&str>) -> Result<bool> {
+    let modifications = local_modifications(&args.dest, target)?;
+    if modifications.is_empty() {
+        return Ok(false);
+    }
+    if args.force {
+        return Ok(true);
+    }
+    Err(ModuleError::PlainMessage(format!(
+        "Local modifications exist in {}, set `force: true` to discard \
+         them:\n  {}",
+        args.dest.display(),
+        modifications.join("\n  ")
+    )))
+}
+
+// The commit `version` names in the remote repository, found without
+// fetching
+fn remote_sha(args: &GitArgs, before: Option<&str>) -> Result<String> {
+    let refs = remote_git(args, None, ["ls-remote", "--", &args.repo])?;
+    let refs: Vec<(&str, &str)> = refs
+        .lines()
+        .filter_map(|line| line.split_once('\t'))
+        .collect();
+    let find = |name: &str| {
+        refs.iter()
+            .find(|(_, reference)| *reference == name)
+            .map(|(sha, _)| sha.to_string())
+    };
+
+    let found = match args.version.as_str() {
+        "HEAD" => find("HEAD"),
+        version => find(&format!("refs/heads/{version}"))
+            .or_else(|| find(&format!("refs/tags/{version}^{{}}")))
+            .or_else(|| find(&format!("refs/tags/{version}"))),
+    };
+    if let Some(sha) = found {
+        return Ok(sha);
+    }
+
+    // Abbreviated SHAs can only be checked against what we already have
+    if !is_named_ref(&args.version) && args.version != "HEAD" {
+        return Ok(match before {
+            Some(before) if before.starts_with(&args.version) => {
+                before.to_string()
+            }
+            _ => args.version.clone(),
+        });
+    }
+    Err(ModuleError::PlainMessage(format!(
+        "Failed to find version `{}` in {}",
+        args.version, args.repo
+    )))
+}
+
+fn not_a_repository(args: &GitArgs) -> Result<bool> {
+    let exists = args.dest.join(".git").exists();
+    if !exists && args.dest.exists() && args.dest.read_dir()?.next().is_some() {
+        return Err(ModuleError::PlainMessage(format!(
+            "Destination {} already exists and is not a git repository",
+            args.dest.display()
+        )));
+    }
+    Ok(!exists)
+}
+
+// Check mode doesn't fetch, so a version which isn't here yet can't have
+// its signature verified
+fn unverified(result: &mut TaskResult) {
+    result
+        .msg
+        .push_str(" (signature not verified before fetching)");
+    result.set("verified", Yaml::Boolean(false));
+}
+
+fn unchanged(msg: &str, before: Option<String>) -> TaskResult {
+    let mut result = TaskResult::new(false).with_msg(msg);
+    result.set("before", before.clone().map_or(Yaml::Null, Yaml::String));
+    result.set("after", before.map_or(Yaml::Null, Yaml::String));
+    result
+}
+
+/// Predicts the outcome of `run` without fetching or touching the checkout
+pub fn check(args: &GitArgs) -> Result<TaskResult> {
+    if args.export {
+        return export::check(args);
+    }
+    if not_a_repository(args)? {
+        let mut result = TaskResult::new(true).with_msg(format!(
+            "Would clone {} to {}",
+            args.repo,
+            args.dest.display()
+        ));
+        result.set("before", Yaml::Null);
+        result.set("after", Yaml::String(remote_sha(args, None)?));
+        if args.verify_commit {
+            unverified(&mut result);
+        }
+        return Ok(result);
+    }
+
+    let before = rev_parse(&args.dest, "HEAD");
+    if !args.update {
+        return Ok(unchanged("Would leave checkout as it was", before));
+    }
+
+    let after = remote_sha(args, before.as_deref())?;
+    let target = rev_parse(&args.dest, &after);
+    // Untracked files stay, and local commits are only lost by moving away
+    // from them, which changes the checkout anyway
+    let discard =
+        ensure_unmodified(args, target.as_deref())? && is_modified(&args.dest)?;
+
+    // Without fetching, only what is already here can be verified
+    let mut signer = None;
+    if let (true, Some(target)) = (args.verify_commit, &target) {
+        let tag = format!("refs/tags/{}", args.version);
+        let revision = match rev_parse(&args.dest, &tag) {
+            Some(sha) => Revision::Tag(args.version.clone(), sha),
+            None => Revision::Detached(target.clone()),
+        };
+        signer = Some(verify(args, &args.dest, &revision)?);
+    }
+
+    let mut result =
+        TaskResult::new(discard || before.as_deref() != Some(&after));
+    result.msg = match discard {
+        true => {
+            format!("Would discard local modifications and check out {after}")
+        }
+        false if result.changed => format!("Would check out {after}"),
+        false => format!("Already at {after}"),
+    };
+    result.set("before", before.map_or(Yaml::Null, Yaml::String));
+    result.set("after", Yaml::String(after));
+    if let Some(signer) = signer {
+        result.set("signer", Yaml::String(signer));
+    }
+    if args.verify_commit && target.is_none() {
+        unverified(&mut result);
+    }
+    Ok(result)
+}
+
+pub fn run(args: &GitArgs) -> Result<TaskResult> {
+    if args.export {
+        return export::run(args);
+    }
+    let exists = !not_a_repository(args)?;
+    let before = match exists {
+        true => rev_parse(&args.dest, "HEAD"),
+        false => None,
+    };
+
+    if exists && !args.update {
+        return Ok(unchanged("Left checkout as it was", before));
+    }
+
+    let submodules_before = submodule_status(&args.dest);
+    if exists {
+        let changed = update_remote(args)?;
+        fetch(args)?;
+        return update_checkout(
+            args,
+            true,
+            before,
+            changed,
+            &submodules_before,
+        );
+    }
+
+    clone(args)?;
+    let result = update_checkout(args, false, None, false, &submodules_before);
+    if result.is_err() {
+        // Don't leave a clone behind that was never checked out. Its empty
+        // index would have later runs take every file for deleted.
+        let _ = std::fs::remove_dir_all(&args.dest);
+    }
+    result
+}
+
+// Moves the checkout at `args.dest` from `before` to `args.version`
+fn update_checkout(
+    args: &GitArgs,
+    exists: bool,
+    before: Option<String>,
+    mut changed: bool,
+    submodules_before: &str,
+) -> Result<TaskResult> {
+    let revision = resolve_version(args, &args.version)?;
+    let signer = match args.verify_commit {
+        true => Some(verify(args, &args.dest, &revision)?),
+        false => None,
+    };
+    // Untracked files are left alone unless the checkout overwrites them,
+    // and local commits are only lost by moving away from them
+    if exists
+        && ensure_unmodified(args, Some(revision.sha()))?
+        && is_modified(&args.dest)?
+    {
+        git(Some(&args.dest), ["reset", "--quiet", "--hard", "HEAD"])?;
+        changed = true;
+    }
+    if before.as_deref() != Some(revision.sha()) || !exists {
+        checkout(&args.dest, &revision, args.force)?;
+    }
+
+    if args.recursive {
+        update_submodules(args)?;
+        changed |= exists && submodule_status(&args.dest) != submodules_before;
+    }
+
+    let after = rev_parse(&args.dest, "HEAD");
+    let mut result = TaskResult::new(before != after || changed);
+    result.msg = match (&before, &after) {
+        (None, _) => format!("Cloned {} to {}", args.repo, args.dest.display()),
+        (Some(before), Some(after)) if before != after => {
+            format!("Updated from {before} to {after}")
+        }
+        _ => format!("Already at {}", revision.sha()),
+    };
+    result.set("before", before.map_or(Yaml::Null, Yaml::String));
+    result.set("after", after.map_or(Yaml::Null, Yaml::String));
+    if let Some(signer) = signer {
+        result.set("signer", Yaml::String(signer));
+    }
+    Ok(result)
+}
+
+pub struct Git;
+
+impl Module for Git {
+    type Args = GitArgs;
+
+    fn parse_args(&self, args: &Yaml) -> Result<Self::Args> {
+        let args = Args::new("git", args, OPTIONS)?;
+        let mut git_args = GitArgs::new(
+            args.required_string("repo")?,
+            args.required_path("dest")?,
+        );
+        if let Some(version) = args.string("version")? {
+            // It ends up on git's command line
+            if version.starts_with('-') {
+                return Err(ModuleError::PlainMessage(format!(
+                    "git: `version` must not start with `-`, got: {version}"
+                )));
+            }
+            git_args.version = version;
+        }
+        git_args.depth = match args.int("depth")? {
+            Some(depth @ 1..) => Some(depth.try_into().unwrap_or(u32::MAX)),
+            Some(_) => {
+                return Err(ModuleError::PlainMessage(
+                    "git: `depth` must be at least 1".to_string(),
+                ))
+            }
+            None => None,
+        };
+        git_args.recursive = args.bool("recursive")?.unwrap_or(true);
+        git_args.update = args.bool("update")?.unwrap_or(true);
+        git_args.force = args.bool("force")?.unwrap_or(false);
+        git_args.single_branch = args.bool("single_branch")?.unwrap_or(false);
+        git_args.verify_commit = args.bool("verify_commit")?.unwrap_or(false);
+        git_args.gpg_home = args.path("gpg_home")?;
+        git_args.allowed_signers = args.path("allowed_signers")?;
+        git_args.export = args.bool("export")?.unwrap_or(false);
+        git_args.key_file = args.path("key_file")?;
+        git_args.ssh_opts = args.string("ssh_opts")?;
+        git_args.accept_hostkey = args.bool("accept_hostkey")?.unwrap_or(false);
+        git_args.accept_newhostkey =
+            args.bool("accept_newhostkey")?.unwrap_or(false);
+        if git_args.accept_hostkey && git_args.accept_newhostkey {
+            return Err(ModuleError::PlainMessage(
+                "git: `accept_hostkey` and `accept_newhostkey` are mutually \
+                 exclusive"
+                    .to_string(),
+            ));
+        }
+        Ok(git_args)
+    }
+
+    fn run(&self, args: Self::Args) -> Result<TaskResult> {
+        run(&args)
+    }
+
+    fn check(&self, args: Self::Args) -> Result<TaskResult> {
+        check(&args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Runs git with a fixed identity, panicking if it fails
+    pub fn git_ok(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args([
+                "-c",
+                "init.defaultBranch=main",
+                "-c",
+                "commit.gpgsign=false",
+            ])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "git {args:?}: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8_lossy(&output.stdout).trim().to_string()
+    }
+
+    /// A bare repository at `<tmp>/origin.git` with a `main` branch of two
+    /// commits, a `v1` tag on the first and a `dev` branch
+    pub struct Fixture {
+        pub tmp: TempDir,
+        pub work: PathBuf,
+        pub origin: PathBuf,
+    }
+
+    impl Fixture {
+        pub fn new() -> Self {
+            let tmp = TempDir::new().unwrap();
+            let origin = tmp.path().join("origin.git");
+            let work = tmp.path().join("work");
+            git_ok(tmp.path(), &["init", "--quiet", "--bare", "origin.git"]);
+            git_ok(tmp.path(), &["init", "--quiet", "work"]);
+            git_ok(
+                &work,
+                &["remote", "add", "origin", origin.to_str().unwrap()],
+            );
+
+            let fixture = Self { tmp, work, origin };
+            fixture.commit("README", "first");
+            git_ok(&fixture.work, &["tag", "v1"]);
+            fixture.commit("README", "second");
+            git_ok(&fixture.work, &["branch", "dev"]);
+            fixture.push();
+            git_ok(
+                &fixture.origin,
+                &["symbolic-ref", "HEAD", "refs/heads/main"],
+            );
+            fixture
+        }
+
+        pub fn commit(&self, file: &str, contents: &str) -> String {
+            std::fs::write(self.work.join(file), contents).unwrap();
+            git_ok(&self.work, &["add", "."]);
+            git_ok(&self.work, &["commit", "--quiet", "-m", contents]);
+            git_ok(&self.work, &["rev-parse", "HEAD"])
+        }
+
+        pub fn push(&self) {
+            git_ok(&self.work, &["push", "--quiet", "--all", "origin"]);
+            git_ok(&self.work, &["push", "--quiet", "--tags", "origin"]);
+        }
+
+        pub fn sha(&self, rev: &str) -> String {
+            git_ok(&self.work, &["rev-parse", &format!("{rev}^{{commit}}")])
+        }
+
+        pub fn args(&self, version: &str) -> GitArgs {
+            let mut args = GitArgs::new(
+                self.origin.to_str().unwrap(),
+                self.tmp.path().join("checkout"),
+            );
+            args.version = version.to_string();
+            args
+        }
+    }
+
+    fn after(result: &TaskResult) -> &str {
+        result.get("after").and_then(Yaml::as_str).unwrap()
+    }
+
+    #[test]
+    fn git_clone_and_update() {
+        let fixture = Fixture::new();
+        let args = fixture.args("HEAD");
+
+        let result = run(&args).unwrap();
+        assert!(result.changed);
+        assert_eq!(result.get("before"), Some(&Yaml::Null));
+        assert_eq!(after(&result), fixture.sha("main"));
+
+        let result = run(&args).unwrap();
+        assert!(!result.changed);
+
+        let sha = fixture.commit("README", "third");
+        fixture.push();
+        let result = run(&args).unwrap();
+        assert!(result.changed);
+        assert_eq!(after(&result), sha);
+    }
+
+    #[test]
+    fn git_versions() {
+        let fixture = Fixture::new();
+
+        let result = run(&fixture.args("v1")).unwrap();
+        assert!(result.changed);
+        assert_eq!(after(&result), fixture.sha("v1"));
+
+        let result = run(&fixture.args("dev")).unwrap();
+        assert!(result.changed);
+        assert_eq!(after(&result), fixture.sha("dev"));
+        let dest = fixture.args("dev").dest;
+        assert_eq!(
+            git(Some(&dest), ["branch", "--show-current"]).unwrap(),
+            "dev"
+        );
+
+        let sha = fixture.sha("v1");
+        let result = run(&fixture.args(&sha)).unwrap();
+        assert!(result.changed);
+        assert_eq!(after(&result), sha);
+
+        assert!(run(&fixture.args("nope")).is_err());
+
+        // A fresh clone of a version which doesn't exist is removed again
+        let mut args = fixture.args("nope");
+        args.dest = fixture.tmp.path().join("fresh");
+        assert!(run(&args).is_err());
+        assert!(!args.dest.exists());
+        args.version = "main".to_string();
+        assert!(run(&args).unwrap().changed);
+    }
+
+    #[test]
+    fn git_shallow_clone() {
+        let fixture = Fixture::new();
+        let mut args = fixture.args("main");
+        // --depth is ignored for plain paths
+        args.repo = format!("file://{}", fixture.origin.display());
+        args.depth = Some(1);
+
+        let result = run(&args).unwrap();
+        assert!(result.changed);
+        let count = git(Some(&args.dest), ["rev-list", "--count", "HEAD"]);
+        assert_eq!(count.unwrap(), "1");
+
+        // Other branches are still available
+        args.version = "dev".to_string();
+        run(&args).unwrap();
+
+        args.version = "main".to_string();
+        args.single_branch = true;
+        args.dest = fixture.tmp.path().join("single");
+        run(&args).unwrap();
+        let branches = git(Some(&args.dest), ["branch", "--remotes"]).unwrap();
+        assert!(branches.contains("origin/main"));
+        assert!(!branches.contains("origin/dev"));
+    }
+
+    #[test]
+    fn git_update_and_force() {
+        let fixture = Fixture::new();
+        let mut args = fixture.args("HEAD");
+        run(&args).unwrap();
+        std::fs::write(args.dest.join("README"), "patched").unwrap();
+
+        let sha = fixture.commit("README", "third");
+        fixture.push();
+
+        args.update = false;
+        let result = run(&args).unwrap();
+        assert!(!result.changed);
+        assert_ne!(after(&result), sha);
+
+        args.update = true;
+        args.force = true;
+        let result = run(&args).unwrap();
+        assert!(result.changed);
+        assert_eq!(after(&result), sha);
+        let readme = std::fs::read_to_string(args.dest.join("README"));
+        assert_eq!(readme.unwrap(), "third");
+    }
+
+    #[test]
+    fn git_local_modifications() {
+        let fixture = Fixture::new();
+        let mut args = fixture.args("HEAD");
+        run(&args).unwrap();
+        assert!(!check(&args).unwrap().changed);
+
+        std::fs::write(args.dest.join("README"), "patched").unwrap();
+        std::fs::write(args.dest.join("notes.txt"), "mine").unwrap();
+        git_ok(
+            &args.dest,
+            &["commit", "--quiet", "-m", "hotfix", "--allow-empty"],
+        );
+
+        let expected =
+            ["modified: README", "untracked: notes.txt", "local commit:"];
+        for result in [run(&args), check(&args)] {
+            let msg = result.unwrap_err().to_string();
+            for expected in expected {
+                assert!(msg.contains(expected), "{expected} not in {msg}");
+            }
+        }
+
+        args.force = true;
+        let result = check(&args).unwrap();
+        assert!(result.changed);
+        assert_eq!(
+            std::fs::read_to_string(args.dest.join("README")).unwrap(),
+            "patched"
+        );
+
+        let result = run(&args).unwrap();
+        assert!(result.changed);
+        assert_eq!(after(&result), fixture.sha("main"));
+        assert_eq!(
+            std::fs::read_to_string(args.dest.join("README")).unwrap(),
+            "second"
+        );
+        assert!(args.dest.join("notes.txt").exists());
+    }
+
+    #[test]
+    fn git_untracked_files() {
+        let fixture = Fixture::new();
+        let mut args = fixture.args("HEAD");
+        args.force = true;
+        run(&args).unwrap();
+        std::fs::write(args.dest.join("build.log"), "output").unwrap();
+
+        // Nothing is discarded, so nothing changes
+        for _ in 0..2 {
+            assert!(!check(&args).unwrap().changed);
+            assert!(!run(&args).unwrap().changed);
+        }
+        assert!(args.dest.join("build.log").exists());
+
+        // Modified tracked files are reset once
+        std::fs::write(args.dest.join("README"), "patched").unwrap();
+        assert!(check(&args).unwrap().changed);
+        assert!(run(&args).unwrap().changed);
+        assert!(!run(&args).unwrap().changed);
+        assert!(args.dest.join("build.log").exists());
+    }
+
+    #[test]
+    fn git_renamed_files() {
+        let fixture = Fixture::new();
+        fixture.commit("ab", "short");
+        fixture.push();
+        let args = fixture.args("HEAD");
+        run(&args).unwrap();
+
+        git_ok(&args.dest, &["mv", "ab", "renamed.txt"]);
+        std::fs::write(args.dest.join("notes.txt"), "mine").unwrap();
+        let modifications = local_modifications(&args.dest, None).unwrap();
+        assert_eq!(
+            modifications,
+            ["renamed: ab -> renamed.txt", "untracked: notes.txt"]
+        );
+    }
+
+    #[test]
+    fn git_check_mode() {
+        let fixture = Fixture::new();
+        let args = fixture.args("v1");
+
+        let result = check(&args).unwrap();
+        assert!(result.changed);
+        assert!(!args.dest.exists());
+        assert_eq!(after(&result), fixture.sha("v1"));
+
+        run(&args).unwrap();
+        assert!(!check(&args).unwrap().changed);
+
+        let result = check(&fixture.args("dev")).unwrap();
+        assert!(result.changed);
+        assert_eq!(after(&result), fixture.sha("dev"));
+        assert!(check(&fixture.args("nope")).is_err());
+    }
+
+    // Signs with a fresh SSH key, returning it and an allowed signers file
+    // trusting it
+    fn signing_key(fixture: &Fixture, name: &str) -> (PathBuf, PathBuf) {
+        let key = fixture.tmp.path().join(name);
+        let status = Command::new("ssh-keygen")
+            .args(["-q", "-t", "ed25519", "-N", "", "-C", name, "-f"])
+            .arg(&key)
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        let public = std::fs::read_to_string(format!("{}.pub", key.display()));
+        let allowed = fixture.tmp.path().join(format!("{name}.allowed"));
+        std::fs::write(&allowed, format!("{name} {}", public.unwrap()))
+            .unwrap();
+        (key, allowed)
+    }
+
+    fn signed(key: &Path) -> [String; 4] {
+        [
+            "-c".to_string(),
+            "gpg.format=ssh".to_string(),
+            "-c".to_string(),
+            format!("user.signingkey={}", key.display()),
+        ]
+    }
+
+    #[test]
+    fn git_verify_commit() {
+        let fixture = Fixture::new();
+        let (key, allowed) = signing_key(&fixture, "deploy@example.com");
+        let (other, _) = signing_key(&fixture, "mallory@example.com");
+
+        let sign = |key: &Path, args: &[&str]| {
+            let mut command: Vec<String> = signed(key).into();
+            command.extend(args.iter().map(|arg| arg.to_string()));
+            let command: Vec<&str> =
+                command.iter().map(|s| s.as_str()).collect();
+            git_ok(&fixture.work, &command);
+        };
+        sign(&key, &["tag", "-s", "-m", "release", "v2"]);
+        sign(
+            &key,
+            &["commit", "--quiet", "-S", "--allow-empty", "-m", "signed"],
+        );
+        sign(&other, &["tag", "-s", "-m", "release", "v3"]);
+        fixture.push();
+
+        let mut args = fixture.args("v2");
+        args.verify_commit = true;
+        args.allowed_signers = Some(allowed);
+
+        let result = run(&args).unwrap();
+        assert_eq!(
+            result.get("signer").and_then(Yaml::as_str),
+            Some("deploy@example.com")
+        );
+        assert_eq!(after(&result), fixture.sha("v2"));
+
+        let result = check(&fixture_version(&args, "main")).unwrap();
+        assert_eq!(
+            result.get("signer").and_then(Yaml::as_str),
+            Some("deploy@example.com")
+        );
+        assert!(run(&fixture_version(&args, "main")).is_ok());
+
+        // Untrusted and unsigned versions are refused, leaving HEAD alone
+        for version in ["v3", "v1"] {
+            let msg = run(&fixture_version(&args, version))
+                .unwrap_err()
+                .to_string();
+            assert!(msg.contains("Failed to verify"), "{msg}");
+            assert_eq!(
+                rev_parse(&args.dest, "HEAD").unwrap(),
+                fixture.sha("main")
+            );
+        }
+
+        // A fresh clone which fails verification is removed again
+        args.dest = fixture.tmp.path().join("fresh");
+        args.version = "v1".to_string();
+        assert!(run(&args).is_err());
+        assert!(!args.dest.exists());
+
+        // Check mode doesn't fetch, so it can't verify a new version
+        fixture.commit("README", "unsigned");
+        fixture.push();
+        let main = GitArgs {
+            dest: fixture.tmp.path().join("checkout"),
+            ..fixture_version(&args, "main")
+        };
+        let result = check(&main).unwrap();
+        assert!(result.changed);
+        assert!(result.msg.starts_with("Would check out"));
+        assert!(result.msg.contains("signature not verified"));
+        assert_eq!(result.get("verified"), Some(&Yaml::Boolean(false)));
+        assert!(result.get("signer").is_none());
+        args.dest = fixture.tmp.path().join("unfetched");
+        let result = check(&args).unwrap();
+        assert_eq!(result.get("verified"), Some(&Yaml::Boolean(false)));
+    }
+
+    fn fixture_version(args: &GitArgs, version: &str) -> GitArgs {
+        GitArgs {
+            version: version.to_string(),
+            ..args.clone()
+        }
+    }
+
+    #[test]
+    fn git_signer() {
+        assert_eq!(
+            signer(
+                "[GNUPG:] NEWSIG\n[GNUPG:] GOODSIG 0123456789ABCDEF Deploy \
+                 <deploy@example.com>\n[GNUPG:] TRUST_ULTIMATE 0 pgp"
+            )
+            .as_deref(),
+            Some("Deploy <deploy@example.com>")
+        );
+        assert_eq!(
+            signer(
+                "Good \"git\" signature for deploy@example.com with ED25519 \
+                 key SHA256:abc"
+            )
+            .as_deref(),
+            Some("deploy@example.com")
+        );
+        assert_eq!(signer("No principal matched."), None);
+    }
+
+    #[test]
+    fn git_ssh_command() {
+        let mut args = GitArgs::new("git@example.com:repo.git", "repo");
+        assert_eq!(ssh_command(&args), None);
+
+        args.key_file = Some(PathBuf::from("/keys/it's"));
+        args.accept_newhostkey = true;
+        args.ssh_opts = Some("-o Port=2222".to_string());
+        assert_eq!(
+            ssh_command(&args).unwrap(),
+            "ssh -i '/keys/it'\\''s' -o IdentitiesOnly=yes \
+             -o StrictHostKeyChecking=accept-new -o Port=2222"
+        );
+
+        args.accept_hostkey = true;
+        assert!(ssh_command(&args)
+            .unwrap()
+            .contains("-o StrictHostKeyChecking=no "));
+    }
+
+    #[test]
+    fn git_clone_over_ssh() {
+        let fixture = Fixture::new();
+
+        // A stand-in for ssh which logs how it was called and runs the
+        // remote command locally
+        let bin = fixture.tmp.path().join("bin");
+        std::fs::create_dir(&bin).unwrap();
+        let ssh = bin.join("ssh");
+        std::fs::write(
+            &ssh,
+            "#!/bin/sh\n\
+             echo \"$@\" >> \"$(dirname \"$0\")/ssh.log\"\n\
+             for last; do :; done\n\
+             exec sh -c \"$last\"\n",
+        )
+        .unwrap();
+        Command::new("chmod").arg("755").arg(&ssh).status().unwrap();
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let mut paths = vec![bin.clone()];
+        paths.extend(std::env::split_paths(&path));
+        let path = std::env::join_paths(paths).unwrap();
+
+        let mut args = fixture.args("HEAD");
+        args.env.push(("PATH".to_string(), path));
+        args.repo =
+            format!("ssh://deploy@stand-in{}", fixture.origin.display());
+        args.key_file = Some(fixture.tmp.path().join("id_deploy"));
+        args.accept_newhostkey = true;
+
+        let result = run(&args).unwrap();
+        assert!(result.changed);
+        assert_eq!(after(&result), fixture.sha("main"));
+        assert!(!check(&args).unwrap().changed);
+
+        let log = std::fs::read_to_string(bin.join("ssh.log")).unwrap();
+        let key =
+            format!("-i {}", fixture.tmp.path().join("id_deploy").display());
+        assert!(log.contains(&key), "{log}");
+        assert!(log.contains("StrictHostKeyChecking=accept-new"), "{log}");
+        assert!(log.contains("deploy@stand-in"), "{log}");
+    }
+
+    #[test]
+    fn git_submodules() {
+        let fixture = Fixture::new();
+        let library = Fixture::new();
+        git_ok(
+            &fixture.work,
+            &[
+                "-c",
+                "protocol.file.allow=always",
+                "submodule",
+                "add",
+                "--quiet",
+                library.origin.to_str().unwrap(),
+                "lib",
+            ],
+        );
+        git_ok(&fixture.work, &["commit", "--quiet", "-m", "submodule"]);
+        fixture.push();
+
+        let mut args = fixture.args("HEAD");
+        // Submodules from local paths are refused by default
+        args.env = [
+            ("GIT_CONFIG_COUNT", "1"),
+            ("GIT_CONFIG_KEY_0", "protocol.file.allow"),
+            ("GIT_CONFIG_VALUE_0", "always"),
+        ]
+        .map(|(key, value)| (key.to_string(), value.into()))
+        .into();
+        run(&args).unwrap();
+        assert!(args.dest.join("lib/README").exists());
+
+        let sha = library.commit("README", "library");
+        library.push();
+        git_ok(
+            &fixture.work.join("lib"),
+            &["pull", "--quiet", "origin", "main"],
+        );
+        git_ok(&fixture.work, &["commit", "--quiet", "-am", "bump"]);
+        fixture.push();
+
+        let result = run(&args).unwrap();
+        assert!(result.changed);
+        assert_eq!(
+            git(Some(&args.dest.join("lib")), ["rev-parse", "HEAD"]).unwrap(),
+            sha
+        );
+
+        args.recursive = false;
+        args.dest = fixture.tmp.path().join("flat");
+        run(&args).unwrap();
+        assert!(!args.dest.join("lib/README").exists());
+    }
+
+    #[test]
+    fn git_args() {
+        let yaml = yaml_rust::YamlLoader::load_from_str(
+            "repo: https://example.com/repo.git\ndest: ~/repo",
+        )
+        .unwrap()
+        .remove(0);
+        let args = Git.parse_args(&yaml).unwrap();
+        assert_eq!(args.version, "HEAD");
+        assert!(args.dest.is_absolute());
+        assert!(args.recursive && args.update && !args.force);
+
+        let yaml =
+            yaml_rust::YamlLoader::load_from_str("repo: r\ndest: d\ndepth: 0")
+                .unwrap()
+                .remove(0);
+        assert!(Git.parse_args(&yaml).is_err());
+
+        let yaml = yaml_rust::YamlLoader::load_from_str(
+            "repo: r\ndest: d\nversion: --upload-pack=touch /tmp/pwned",
+        )
+        .unwrap()
+        .remove(0);
+        assert!(Git.parse_args(&yaml).is_err());
+
+        let yaml = yaml_rust::YamlLoader::load_from_str("dest: repo")
+            .unwrap()
+            .remove(0);
+        assert!(Git.parse_args(&yaml).is_err());
     }
 }
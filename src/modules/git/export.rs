@@ -0,0 +1,410 @@
+// Exports a single revision of a repository, without its history, as a
+// plain tree or an archive.
+//
+// The exported commit is recorded next to the export, in `.git-revision`
+// inside a tree or `<dest>.revision` beside an archive, so later runs only
+// re-export when `version` resolves to a different commit.
+use super::{
+    git, remote_git, remote_sha, rev_parse, unchanged, unverified, verify,
+    GitArgs, Revision,
+};
+use crate::modules::archive::{compress, ArchiveType};
+use crate::modules::temp::staging_path;
+use crate::modules::{ModuleError, Result, TaskResult};
+use yaml_rust::Yaml;
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{ChildStdout, Command, Stdio};
+
+enum Target {
+    Tree,
+    Archive(ArchiveType),
+}
+
+impl Target {
+    fn of(dest: &Path) -> Self {
+        match ArchiveType::match_extension(dest) {
+            Ok(archive_type) => Self::Archive(archive_type),
+            Err(_) => Self::Tree,
+        }
+    }
+
+    fn stamp(&self, dest: &Path) -> PathBuf {
+        match self {
+            Self::Tree => dest.join(".git-revision"),
+            Self::Archive(_) => {
+                let mut stamp = dest.as_os_str().to_owned();
+                stamp.push(".revision");
+                PathBuf::from(stamp)
+            }
+        }
+    }
+}
+
+// Tarballs are compressed in-process, so only formats with an encoder can
+// be exported to
+fn ensure_exportable(archive_type: &ArchiveType) -> Result<()> {
+    match archive_type {
+        ArchiveType::Zip
+        | ArchiveType::Tar
+        | ArchiveType::TarGzip
+        | ArchiveType::TarBzip2
+        | ArchiveType::TarXz
+        | ArchiveType::TarZstd => Ok(()),
+        _ => Err(ModuleError::PlainMessage(format!(
+            "Exporting to {archive_type} archives is not supported"
+        ))),
+    }
+}
+
+// Anything at `dest` which no earlier export left there is not replaced
+fn ensure_replaceable(args: &GitArgs, before: Option<&str>) -> Result<()> {
+    let dest = &args.dest;
+    let empty_dir = dest.is_dir() && dest.read_dir()?.next().is_none();
+    if before.is_none() && dest.exists() && !empty_dir {
+        return Err(ModuleError::PlainMessage(format!(
+            "Destination {} already exists and is not an export",
+            dest.display()
+        )));
+    }
+    Ok(())
+}
+
+fn read_stamp(stamp: &Path) -> Option<String> {
+    let sha = fs::read_to_string(stamp).ok()?;
+    Some(sha.trim().to_string())
+}
+
+// Fetches just enough of the repository into `scratch` to export `version`
+fn fetch_version(args: &GitArgs, scratch: &Path) -> Result<Revision> {
+    git(Some(scratch), ["init", "--quiet", "--bare"])?;
+    let depth = format!("--depth={}", args.depth.unwrap_or(1));
+    let fetch = |refspec: &str| {
+        remote_git(
+            args,
+            Some(scratch),
+            ["fetch", "--quiet", &depth, "--", &args.repo, refspec],
+        )
+    };
+
+    let version = &args.version;
+    if version != "HEAD"
+        && fetch(&format!("+refs/tags/{version}:refs/tags/{version}")).is_ok()
+    {
+        if let Some(sha) = rev_parse(scratch, &format!("refs/tags/{version}")) {
+            return Ok(Revision::Tag(version.clone(), sha));
+        }
+    }
+    if fetch(version).is_ok() {
+        if let Some(sha) = rev_parse(scratch, "FETCH_HEAD") {
+            return Ok(Revision::Detached(sha));
+        }
+    }
+
+    // Abbreviated SHAs can't be fetched directly, so fetch everything
+    remote_git(
+        args,
+        Some(scratch),
+        [
+            "fetch",
+            "--quiet",
+            "--",
+            &args.repo,
+            "+refs/heads/*:refs/heads/*",
+            "+refs/tags/*:refs/tags/*",
+        ],
+    )?;
+    rev_parse(scratch, version)
+        .map(Revision::Detached)
+        .ok_or_else(|| {
+            ModuleError::PlainMessage(format!(
+                "Failed to find version `{version}` in {}",
+                args.repo
+            ))
+        })
+}
+
+// Streams the tarball `git archive` makes of `sha` into `read`
+fn export_tar(
+    scratch: &Path,
+    sha: &str,
+    dest: &Path,
+    read: impl FnOnce(ChildStdout) -> io::Result<()>,
+) -> Result<()> {
+    let mut archive = Command::new("git")
+        .arg("-C")
+        .arg(scratch)
+        .args(["archive", "--format=tar", sha])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            ModuleError::PlainMessage(format!("Failed to execute git: {e}"))
+        })?;
+    let stdout = archive.stdout.take().expect("stdout is piped");
+    let read = read(stdout);
+    let archived = archive.wait_with_output()?;
+
+    let error = match read {
+        Err(e) => e.to_string(),
+        Ok(()) if !archived.status.success() => {
+            String::from_utf8_lossy(&archived.stderr).trim().to_string()
+        }
+        Ok(()) => return Ok(()),
+    };
+    Err(ModuleError::PlainMessage(format!(
+        "Failed to export {sha} to {}: {error}",
+        dest.display()
+    )))
+}
+
+fn export_tree(scratch: &Path, sha: &str, dest: &Path) -> Result<()> {
+    let staging = staging_path(dest);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    fs::create_dir_all(&staging)?;
+
+    let unpack = |tar| tar::Archive::new(tar).unpack(&staging);
+    if let Err(e) = export_tar(scratch, sha, dest, unpack) {
+        fs::remove_dir_all(&staging)?;
+        return Err(e);
+    }
+
+    // Only an earlier export or an empty directory, see `ensure_replaceable`
+    if dest.exists() {
+        fs::remove_dir_all(dest)?;
+    }
+    fs::rename(&staging, dest)?;
+    Ok(())
+}
+
+fn export_archive(
+    scratch: &Path,
+    sha: &str,
+    dest: &Path,
+    archive_type: &ArchiveType,
+) -> Result<()> {
+    let staging = std::path::absolute(staging_path(dest))?;
+    let exported = match archive_type {
+        // git writes zip files itself, without any other program
+        ArchiveType::Zip => {
+            let output = format!("--output={}", staging.display());
+            git(Some(scratch), ["archive", "--format=zip", &output, sha])
+                .map(|_| ())
+        }
+        _ => export_tar(scratch, sha, dest, |tar| {
+            compress(archive_type, tar, &staging)
+        }),
+    };
+    if let Err(e) = exported {
+        let _ = fs::remove_file(&staging);
+        return Err(e);
+    }
+
+    fs::rename(&staging, dest)?;
+    Ok(())
+}
+
+pub fn check(args: &GitArgs) -> Result<TaskResult> {
+    let target = Target::of(&args.dest);
+    if let Target::Archive(archive_type) = &target {
+        ensure_exportable(archive_type)?;
+    }
+    let before = read_stamp(&target.stamp(&args.dest));
+    if before.is_some() && !args.update {
+        return Ok(unchanged("Would leave export as it was", before));
+    }
+    ensure_replaceable(args, before.as_deref())?;
+
+    let after = remote_sha(args, before.as_deref())?;
+    let mut result = TaskResult::new(before.as_deref() != Some(&after));
+    result.msg = match result.changed {
+        true => format!("Would export {after} to {}", args.dest.display()),
+        false => format!("Already exported {after}"),
+    };
+    result.set("before", before.map_or(Yaml::Null, Yaml::String));
+    result.set("after", Yaml::String(after));
+    if args.verify_commit && result.changed {
+        unverified(&mut result);
+    }
+    Ok(result)
+}
+
+pub fn run(args: &GitArgs) -> Result<TaskResult> {
+    let target = Target::of(&args.dest);
+    if let Target::Archive(archive_type) = &target {
+        ensure_exportable(archive_type)?;
+    }
+    let stamp = target.stamp(&args.dest);
+    let before = read_stamp(&stamp);
+    if before.is_some() && !args.update {
+        return Ok(unchanged("Left export as it was", before));
+    }
+    ensure_replaceable(args, before.as_deref())?;
+
+    // Asking the remote is much cheaper than fetching when nothing changed
+    if !args.verify_commit {
+        if let Ok(after) = remote_sha(args, before.as_deref()) {
+            if before.as_deref() == Some(&after) {
+                return Ok(unchanged("Already exported", before));
+            }
+        }
+    }
+
+    let scratch = tempfile::Builder::new().prefix("rustible-git-").tempdir()?;
+    let revision = fetch_version(args, scratch.path())?;
+    let signer = match args.verify_commit {
+        true => Some(verify(args, scratch.path(), &revision)?),
+        false => None,
+    };
+
+    let sha = revision.sha();
+    let changed = before.as_deref() != Some(sha);
+    if changed {
+        if let Some(parent) = args.dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match &target {
+            Target::Tree => export_tree(scratch.path(), sha, &args.dest)?,
+            Target::Archive(archive_type) => {
+                export_archive(scratch.path(), sha, &args.dest, archive_type)?
+            }
+        }
+        fs::write(&stamp, format!("{sha}\n"))?;
+    }
+
+    let mut result = TaskResult::new(changed);
+    result.msg = match changed {
+        true => format!("Exported {sha} to {}", args.dest.display()),
+        false => format!("Already exported {sha}"),
+    };
+    result.set("before", before.map_or(Yaml::Null, Yaml::String));
+    result.set("after", Yaml::String(sha.to_string()));
+    if let Some(signer) = signer {
+        result.set("signer", Yaml::String(signer));
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::Fixture;
+    use super::*;
+    use std::io::Read;
+
+    #[test]
+    fn export_tree_by_sha() {
+        let fixture = Fixture::new();
+        let mut args = fixture.args("v1");
+        args.export = true;
+
+        let result = check(&args).unwrap();
+        assert!(result.changed);
+        assert!(!args.dest.exists());
+
+        let result = run(&args).unwrap();
+        assert!(result.changed);
+        assert!(!args.dest.join(".git").exists());
+        let readme = fs::read_to_string(args.dest.join("README")).unwrap();
+        assert_eq!(readme, "first");
+        let stamp = read_stamp(&args.dest.join(".git-revision"));
+        assert_eq!(stamp.unwrap(), fixture.sha("v1"));
+
+        assert!(!run(&args).unwrap().changed);
+        assert!(!check(&args).unwrap().changed);
+
+        args.version = fixture.sha("main");
+        let result = run(&args).unwrap();
+        assert!(result.changed);
+        let readme = fs::read_to_string(args.dest.join("README")).unwrap();
+        assert_eq!(readme, "second");
+    }
+
+    // The README in an exported archive
+    fn readme(archive: &Path) -> String {
+        let file = fs::File::open(archive).unwrap();
+        let mut readme = String::new();
+        let stream: Box<dyn Read> = match ArchiveType::match_extension(archive)
+            .unwrap()
+        {
+            ArchiveType::Zip => {
+                let mut zip = zip::ZipArchive::new(file).unwrap();
+                zip.by_name("README")
+                    .unwrap()
+                    .read_to_string(&mut readme)
+                    .unwrap();
+                return readme;
+            }
+            ArchiveType::TarGzip => {
+                Box::new(flate2::read::GzDecoder::new(file))
+            }
+            ArchiveType::TarBzip2 => {
+                Box::new(bzip2::read::BzDecoder::new(file))
+            }
+            ArchiveType::TarXz => Box::new(xz2::read::XzDecoder::new(file)),
+            ArchiveType::TarZstd => Box::new(zstd::Decoder::new(file).unwrap()),
+            _ => Box::new(file),
+        };
+        let mut tar = tar::Archive::new(stream);
+        let mut entries = tar.entries().unwrap().map(|entry| entry.unwrap());
+        let mut entry = entries
+            .find(|entry| entry.path().unwrap() == Path::new("README"))
+            .unwrap();
+        entry.read_to_string(&mut readme).unwrap();
+        readme
+    }
+
+    #[test]
+    fn export_archives() {
+        let fixture = Fixture::new();
+        for extension in
+            ["tar", "tar.gz", "zip", "tar.bz2", "tar.xz", "tar.zst"]
+        {
+            let mut args = fixture.args("main");
+            args.export = true;
+            args.dest = fixture.tmp.path().join(format!("out.{extension}"));
+
+            let result = run(&args).unwrap();
+            assert!(result.changed, "{extension}");
+            assert_eq!(readme(&args.dest), "second", "{extension}");
+            assert!(!run(&args).unwrap().changed, "{extension}");
+        }
+
+        // Formats without a native encoder
+        for extension in ["rar", "tar.lz"] {
+            let mut args = fixture.args("main");
+            args.export = true;
+            args.dest = fixture.tmp.path().join(format!("out.{extension}"));
+            let msg = run(&args).unwrap_err().to_string();
+            assert!(msg.contains("is not supported"), "{msg}");
+            assert!(!args.dest.exists());
+        }
+    }
+
+    #[test]
+    fn export_refuses_other_files() {
+        let fixture = Fixture::new();
+        let mut args = fixture.args("main");
+        args.export = true;
+        fs::create_dir(&args.dest).unwrap();
+        fs::write(args.dest.join("data.txt"), "keep").unwrap();
+
+        for result in [check(&args), run(&args)] {
+            let msg = result.unwrap_err().to_string();
+            assert!(msg.contains("is not an export"), "{msg}");
+        }
+        assert!(args.dest.join("data.txt").exists());
+
+        // Empty directories are exported into
+        fs::remove_file(args.dest.join("data.txt")).unwrap();
+        assert!(run(&args).unwrap().changed);
+
+        args.dest = fixture.tmp.path().join("data.zip");
+        fs::write(&args.dest, "keep").unwrap();
+        assert!(run(&args).is_err());
+        assert_eq!(fs::read_to_string(&args.dest).unwrap(), "keep");
+    }
+}
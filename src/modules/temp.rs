@@ -0,0 +1,92 @@
+// Temporary files modules work with: downloads, and files staged next to
+// where they end up so that they can be moved into place in one step
+use super::{ModuleError, Result};
+use tempfile::TempDir;
+
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub fn is_url(src: &str) -> bool {
+    ["http://", "https://", "ftp://"]
+        .iter()
+        .any(|scheme| src.starts_with(scheme))
+}
+
+/// A downloaded file, removed again when dropped
+pub struct Download {
+    _dir: TempDir,
+    path: PathBuf,
+}
+
+impl Download {
+    /// Downloads `url` to a file called `name`, by default the URL's own
+    /// file name
+    pub fn fetch(url: &str, name: Option<&str>) -> Result<Self> {
+        let dir = tempfile::Builder::new().prefix("rustible-").tempdir()?;
+        let name = name
+            .or_else(|| url.split(['?', '#']).next()?.rsplit('/').next())
+            .filter(|name| !name.is_empty() && *name != "..")
+            .unwrap_or("download");
+        let path = dir.path().join(name);
+        let output = Command::new("curl")
+            .args(["--fail", "--silent", "--show-error", "--location"])
+            .arg("--output")
+            .arg(&path)
+            .arg(url)
+            .output()
+            .map_err(|e| {
+                ModuleError::PlainMessage(format!("Failed to run curl: {e}"))
+            })?;
+        if !output.status.success() {
+            return Err(ModuleError::PlainMessage(format!(
+                "Failed to download {url}: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(Self { _dir: dir, path })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// A hidden sibling of `dest` to build it in before moving it into place
+pub fn staging_path(dest: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(dest.file_name().unwrap_or_default());
+    name.push(".rustible-tmp");
+    dest.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn temp_paths() {
+        assert!(is_url("https://example.com/tool.deb"));
+        assert!(!is_url("./tool.deb"));
+        assert_eq!(
+            staging_path(Path::new("/srv/site.tar.gz")),
+            Path::new("/srv/.site.tar.gz.rustible-tmp")
+        );
+    }
+
+    #[test]
+    fn temp_download() {
+        let src = std::path::absolute("resources/test.txt").unwrap();
+        let url = format!("file://{}?raw", src.display());
+        let download = Download::fetch(&url, None).unwrap();
+        let path = download.path().to_path_buf();
+        assert_eq!(path.file_name().unwrap(), "test.txt");
+        assert_eq!(std::fs::read(&path).unwrap(), std::fs::read(&src).unwrap());
+        drop(download);
+        assert!(!path.exists());
+
+        let download = Download::fetch(&url, Some("package.deb")).unwrap();
+        assert_eq!(download.path().file_name().unwrap(), "package.deb");
+        assert!(Download::fetch("file:///nonexistent/file", None).is_err());
+    }
+}
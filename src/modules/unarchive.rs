@@ -0,0 +1,740 @@
+// Extracts archives into an existing directory.
+//
+// Archives aren't trusted: every member is checked before anything is
+// written, and the archive is refused when a member would end up outside
+// `dest`, by its path or as a link pointing out of it, also by way of the
+// archive's other links. What a failed extraction wrote is removed again.
+mod external;
+mod native;
+
+use super::archive::{determine_archive_type, ArchiveType};
+use super::args::Args;
+use super::temp::{is_url, Download};
+use super::{Module, ModuleError, Result, TaskResult};
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+const OPTIONS: &[&str] = &["src", "dest", "creates", "remote_src"];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnarchiveArgs {
+    /// The archive. With `remote_src` this may also be a URL, which is
+    /// downloaded first
+    pub src: String,
+    /// Directory to extract into, which must already exist
+    pub dest: PathBuf,
+    /// Skip extraction when this path exists
+    pub creates: Option<PathBuf>,
+    /// `src` is already on the managed host. As rustible runs on the host
+    /// it manages, this only decides whether URLs are accepted.
+    pub remote_src: bool,
+}
+
+impl UnarchiveArgs {
+    pub fn new(src: String, dest: PathBuf) -> Self {
+        Self {
+            src,
+            dest,
+            creates: None,
+            remote_src: false,
+        }
+    }
+}
+
+/// The error for an external program which couldn't be started
+fn spawn_error(
+    program: &str,
+    archive_type: &ArchiveType,
+    e: io::Error,
+) -> ModuleError {
+    match e.kind() {
+        io::ErrorKind::NotFound => ModuleError::PlainMessage(format!(
+            "Extracting {archive_type} archives requires `{program}`, which \
+             was not found"
+        )),
+        _ => ModuleError::PlainMessage(format!(
+            "Failed to execute {program}: {e}"
+        )),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Kind {
+    File,
+    Dir,
+    /// A symbolic link, with its target relative to the link's directory
+    Symlink(String),
+    /// A hard link, with its target relative to the archive's root
+    Hardlink(String),
+}
+
+/// A member of an archive, with what the archive records about it. Sizes
+/// and times are only known for files.
+#[derive(Debug, Clone, PartialEq)]
+struct Member {
+    name: String,
+    kind: Kind,
+    size: Option<u64>,
+    /// Seconds since the epoch
+    mtime: Option<u64>,
+    mode: Option<u32>,
+}
+
+impl Member {
+    fn new(name: String, kind: Kind) -> Self {
+        Self {
+            name,
+            kind,
+            size: None,
+            mtime: None,
+            mode: None,
+        }
+    }
+
+    // Whether the member is missing from `dest` or differs from what is
+    // there in anything the archive records
+    fn differs(&self, dest: &Path) -> bool {
+        let path = dest.join(&self.name);
+        let Ok(metadata) = path.symlink_metadata() else {
+            return true;
+        };
+        let same_kind = match &self.kind {
+            Kind::File | Kind::Hardlink(_) => metadata.is_file(),
+            Kind::Dir => metadata.is_dir(),
+            Kind::Symlink(target) => {
+                fs::read_link(&path).is_ok_and(|link| link == Path::new(target))
+            }
+        };
+        if !same_kind {
+            return true;
+        }
+        if let Kind::Symlink(_) = self.kind {
+            // The mode and time of a link aren't extracted
+            return false;
+        }
+
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|mtime| mtime.duration_since(UNIX_EPOCH).ok())
+            .map(|mtime| mtime.as_secs());
+        // Only the permission bits are extracted
+        let mode = metadata.permissions().mode() & 0o777;
+        self.size.is_some_and(|size| size != metadata.len())
+            || self.mtime.is_some_and(|expected| Some(expected) != mtime)
+            || self.mode.is_some_and(|expected| expected & 0o777 != mode)
+    }
+
+    fn to_yaml(&self) -> Yaml {
+        let mut hash = Hash::new();
+        let mut insert = |key: &str, value: Yaml| {
+            hash.insert(Yaml::String(key.to_string()), value);
+        };
+        let (kind, link) = match &self.kind {
+            Kind::File => ("file", None),
+            Kind::Dir => ("directory", None),
+            Kind::Symlink(target) => ("symlink", Some(target)),
+            Kind::Hardlink(target) => ("hardlink", Some(target)),
+        };
+        let int = |value: Option<u64>| {
+            value.map_or(Yaml::Null, |value| {
+                Yaml::Integer(value.try_into().unwrap_or(i64::MAX))
+            })
+        };
+        insert("name", Yaml::String(self.name.clone()));
+        insert("type", Yaml::String(kind.to_string()));
+        if let Some(target) = link {
+            insert("link", Yaml::String(target.clone()));
+        }
+        insert("size", int(self.size));
+        insert("mtime", int(self.mtime));
+        let mode = self.mode.map(|mode| format!("{:04o}", mode & 0o7777));
+        insert("mode", mode.map_or(Yaml::Null, Yaml::String));
+        Yaml::Hash(hash)
+    }
+}
+
+// Archives with more links in a row than this are taken to loop
+const MAX_LINKS: usize = 40;
+
+// `path` without `.` components or a trailing separator
+fn lexical(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| matches!(component, Component::Normal(_)))
+        .collect()
+}
+
+// The symbolic links in an archive, by where they are extracted to
+fn symlinks(members: &[Member]) -> HashMap<PathBuf, &str> {
+    members
+        .iter()
+        .filter_map(|member| match &member.kind {
+            Kind::Symlink(target) => {
+                Some((lexical(Path::new(&member.name)), target.as_str()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Resolves `path`, relative to `dest`, following the archive's own `links`
+// as extraction leaves them. `None` when it would lead out of `dest`.
+fn resolve<'a>(
+    links: &HashMap<PathBuf, &'a str>,
+    path: &'a Path,
+) -> Option<PathBuf> {
+    let mut pending: Vec<Component> = path.components().rev().collect();
+    let mut resolved = PathBuf::new();
+    let mut followed = 0;
+    while let Some(component) = pending.pop() {
+        match component {
+            Component::Normal(part) => {
+                resolved.push(part);
+                if let Some(target) = links.get(&resolved) {
+                    followed += 1;
+                    if followed > MAX_LINKS {
+                        return None;
+                    }
+                    resolved.pop();
+                    pending.extend(Path::new(*target).components().rev());
+                }
+            }
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !resolved.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    Some(resolved)
+}
+
+// Why extracting `member` would write outside `dest`, if it would. Links
+// are followed through the archive's other symlinks in `links`.
+fn escape(
+    member: &Member,
+    links: &HashMap<PathBuf, &str>,
+) -> Option<&'static str> {
+    let name = Path::new(&member.name);
+    if name.has_root() {
+        return Some("absolute path");
+    }
+    if name.components().any(|part| part == Component::ParentDir) {
+        return Some("`..` in path");
+    }
+    // Would be written wherever the link ends up pointing
+    let mut ancestors = name.ancestors().skip(1);
+    if ancestors.any(|ancestor| links.contains_key(&lexical(ancestor))) {
+        return Some("path through a symlink");
+    }
+    match &member.kind {
+        Kind::Symlink(target) => {
+            let target = name.parent().unwrap_or(Path::new("")).join(target);
+            resolve(links, &target)
+                .is_none()
+                .then_some("symlink out of dest")
+        }
+        Kind::Hardlink(target) => resolve(links, Path::new(target))
+            .is_none()
+            .then_some("hard link out of dest"),
+        Kind::File | Kind::Dir => None,
+    }
+}
+
+// Fails naming every member which would escape `dest`
+fn check_members(src: &str, dest: &Path, members: &[Member]) -> Result<()> {
+    let links = symlinks(members);
+    let escaping: Vec<String> = members
+        .iter()
+        .filter_map(|member| {
+            let reason = escape(member, &links)?;
+            Some(match &member.kind {
+                Kind::Symlink(target) | Kind::Hardlink(target) => {
+                    format!("{} -> {target} ({reason})", member.name)
+                }
+                Kind::File | Kind::Dir => {
+                    format!("{} ({reason})", member.name)
+                }
+            })
+        })
+        .collect();
+    if escaping.is_empty() {
+        return Ok(());
+    }
+    Err(ModuleError::PlainMessage(format!(
+        "Refusing to extract {src} into {}, these entries would escape it: {}",
+        dest.display(),
+        escaping.join(", ")
+    )))
+}
+
+// The paths in `dest` which extracting `members` creates, only the top of
+// each new directory tree
+fn new_paths(dest: &Path, members: &[Member]) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = members
+        .iter()
+        .filter_map(|member| {
+            let name = lexical(Path::new(&member.name));
+            let mut path = dest.to_path_buf();
+            for part in name.components() {
+                path.push(part);
+                if path.symlink_metadata().is_err() {
+                    return Some(path);
+                }
+            }
+            None
+        })
+        .collect();
+    paths.sort();
+    paths.dedup();
+    paths
+}
+
+// Removes what a failed extraction left behind
+fn remove_paths(paths: &[PathBuf]) {
+    for path in paths {
+        let _ = match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+            _ => fs::remove_file(path),
+        };
+    }
+}
+
+fn yaml_files(files: &[String]) -> Yaml {
+    Yaml::Array(files.iter().cloned().map(Yaml::String).collect())
+}
+
+fn unchanged(creates: &Path) -> TaskResult {
+    TaskResult::new(false)
+        .with_msg(format!("Skipped extraction, {} exists", creates.display()))
+}
+
+fn run(args: &UnarchiveArgs, check_mode: bool) -> Result<TaskResult> {
+    if let Some(creates) = &args.creates {
+        if creates.exists() {
+            return Ok(unchanged(creates));
+        }
+    }
+    if !args.dest.is_dir() {
+        return Err(ModuleError::PlainMessage(format!(
+            "Destination {} is not a directory",
+            args.dest.display()
+        )));
+    }
+
+    let download = match is_url(&args.src) {
+        // Keeps the file name, whose extension tells the archive type
+        true => Some(Download::fetch(&args.src, None)?),
+        false => None,
+    };
+    let src = match &download {
+        Some(download) => download.path().to_path_buf(),
+        None => PathBuf::from(&args.src),
+    };
+    let archive_type = determine_archive_type(&src.to_string_lossy())?;
+
+    let external =
+        matches!(archive_type, ArchiveType::Rar | ArchiveType::SevenZip);
+    let members = match external {
+        true => external::members(&archive_type, &src)?,
+        false => native::members(&archive_type, &src)?,
+    };
+    check_members(&args.src, &args.dest, &members)?;
+
+    // Extracting everything when anything differs keeps hard links, which
+    // need their targets extracted alongside them, working
+    let changed = members.iter().any(|member| member.differs(&args.dest));
+    if changed && !check_mode {
+        let created = new_paths(&args.dest, &members);
+        let extracted = match external {
+            true => external::extract(&archive_type, &src, &args.dest),
+            false => native::extract(&archive_type, &src, &args.dest, &members),
+        };
+        if let Err(e) = extracted {
+            remove_paths(&created);
+            return Err(e);
+        }
+    }
+    let files: Vec<String> = match changed {
+        true => members.iter().map(|member| member.name.clone()).collect(),
+        false => vec![],
+    };
+
+    let mut result = TaskResult::new(changed);
+    let (src, dest) = (&args.src, args.dest.display());
+    result.msg = match (changed, check_mode) {
+        (true, true) => format!("Would extract {src} into {dest}"),
+        (true, false) => format!("Extracted {src} into {dest}"),
+        (false, _) => format!("{src} is already extracted into {dest}"),
+    };
+    result.set("src", Yaml::String(args.src.clone()));
+    result.set("dest", Yaml::String(args.dest.display().to_string()));
+    result.set("archive_type", Yaml::String(archive_type.to_string()));
+    result.set("files", yaml_files(&files));
+    let list_files = members.iter().map(Member::to_yaml).collect();
+    result.set("list_files", Yaml::Array(list_files));
+    Ok(result)
+}
+
+pub struct Unarchive;
+
+impl Module for Unarchive {
+    type Args = UnarchiveArgs;
+
+    fn parse_args(&self, args: &Yaml) -> Result<Self::Args> {
+        let args = Args::new("unarchive", args, OPTIONS)?;
+        let mut unarchive_args = UnarchiveArgs::new(
+            args.required_string("src")?,
+            args.required_path("dest")?,
+        );
+        unarchive_args.creates = args.path("creates")?;
+        unarchive_args.remote_src = args.bool("remote_src")?.unwrap_or(false);
+        if is_url(&unarchive_args.src) && !unarchive_args.remote_src {
+            return Err(ModuleError::PlainMessage(
+                "unarchive: `src` can only be a URL with `remote_src: true`"
+                    .to_string(),
+            ));
+        }
+        Ok(unarchive_args)
+    }
+
+    fn run(&self, args: Self::Args) -> Result<TaskResult> {
+        run(&args, false)
+    }
+
+    fn check(&self, args: Self::Args) -> Result<TaskResult> {
+        run(&args, true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(src: &str, dest: &Path) -> UnarchiveArgs {
+        UnarchiveArgs::new(src.to_string(), dest.to_path_buf())
+    }
+
+    #[test]
+    fn unarchive_extract() {
+        let expected = fs::read_to_string("resources/test.txt").unwrap();
+        for ext in ["tar", "tar.gz", "tar.bz2", "tar.xz", "zip"] {
+            let tmp = tempfile::tempdir().unwrap();
+            let src = format!("resources/test.{ext}");
+
+            let result = run(&args(&src, tmp.path()), true).unwrap();
+            assert!(result.changed);
+            assert!(!tmp.path().join("test.txt").exists());
+
+            let result = run(&args(&src, tmp.path()), false).unwrap();
+            assert!(result.changed, "{ext}");
+            assert_eq!(
+                result.get("files"),
+                Some(&yaml_files(&["test.txt".to_string()]))
+            );
+            let extracted = fs::read_to_string(tmp.path().join("test.txt"));
+            assert_eq!(extracted.unwrap(), expected, "{ext}");
+        }
+    }
+
+    #[test]
+    fn unarchive_idempotent() {
+        for ext in ["tar.gz", "zip"] {
+            let tmp = tempfile::tempdir().unwrap();
+            let src = format!("resources/test.{ext}");
+            let extracted = tmp.path().join("test.txt");
+            assert!(run(&args(&src, tmp.path()), false).unwrap().changed);
+
+            let result = run(&args(&src, tmp.path()), false).unwrap();
+            assert!(!result.changed, "{ext}");
+            assert_eq!(result.get("files"), Some(&Yaml::Array(vec![])));
+            let Some(Yaml::Array(listed)) = result.get("list_files") else {
+                panic!("{ext}: list_files is missing");
+            };
+            assert_eq!(listed[0]["name"].as_str(), Some("test.txt"));
+            assert_eq!(listed[0]["size"].as_i64(), Some(20));
+
+            // The mode, then the contents and with them the time differ
+            let permissions = fs::Permissions::from_mode(0o600);
+            fs::set_permissions(&extracted, permissions).unwrap();
+            assert!(run(&args(&src, tmp.path()), true).unwrap().changed);
+            assert!(run(&args(&src, tmp.path()), false).unwrap().changed);
+            fs::write(&extracted, "This is a different\n").unwrap();
+            assert!(run(&args(&src, tmp.path()), false).unwrap().changed);
+            assert!(!run(&args(&src, tmp.path()), false).unwrap().changed);
+        }
+    }
+
+    #[test]
+    fn unarchive_idempotent_links() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("links.tar");
+        let mut builder = tar::Builder::new(fs::File::create(&src).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Directory);
+        header.set_mode(0o750);
+        header.set_size(0);
+        builder
+            .append_data(&mut header, "docs/", io::empty())
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o640);
+        header.set_mtime(1_720_884_300);
+        header.set_size(4);
+        builder
+            .append_data(&mut header, "docs/a.txt", &b"text"[..])
+            .unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        builder
+            .append_link(&mut header, "docs/link", "a.txt")
+            .unwrap();
+        builder.into_inner().unwrap();
+
+        let dest = tmp.path().join("dest");
+        fs::create_dir(&dest).unwrap();
+        let src = src.to_string_lossy();
+        assert!(run(&args(&src, &dest), false).unwrap().changed);
+        assert!(!run(&args(&src, &dest), false).unwrap().changed);
+
+        fs::remove_file(dest.join("docs/link")).unwrap();
+        std::os::unix::fs::symlink("/etc", dest.join("docs/link")).unwrap();
+        assert!(run(&args(&src, &dest), false).unwrap().changed);
+        let link = fs::read_link(dest.join("docs/link")).unwrap();
+        assert_eq!(link, Path::new("a.txt"));
+        assert!(!run(&args(&src, &dest), false).unwrap().changed);
+    }
+
+    #[test]
+    fn unarchive_zip_dir_modes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let src = tmp.path().join("private.zip");
+        let mut writer = zip::ZipWriter::new(fs::File::create(&src).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        writer
+            .add_directory("private", options.unix_permissions(0o700))
+            .unwrap();
+        writer
+            .start_file("private/key", options.unix_permissions(0o600))
+            .unwrap();
+        io::Write::write_all(&mut writer, b"secret").unwrap();
+        writer.finish().unwrap();
+
+        let dest = tmp.path().join("dest");
+        fs::create_dir(&dest).unwrap();
+        let src = src.to_string_lossy();
+        assert!(run(&args(&src, &dest), false).unwrap().changed);
+        let mode = |path: &str| {
+            let metadata = fs::metadata(dest.join(path)).unwrap();
+            metadata.permissions().mode() & 0o777
+        };
+        assert_eq!(mode("private"), 0o700);
+        assert_eq!(mode("private/key"), 0o600);
+        assert!(!run(&args(&src, &dest), true).unwrap().changed);
+        assert!(!run(&args(&src, &dest), false).unwrap().changed);
+    }
+
+    #[test]
+    fn unarchive_creates() {
+        let tmp = tempfile::tempdir().unwrap();
+        let mut unarchive_args = args("resources/test.tar", tmp.path());
+        unarchive_args.creates = Some(tmp.path().to_path_buf());
+        let result = run(&unarchive_args, false).unwrap();
+        assert!(!result.changed);
+        assert!(!tmp.path().join("test.txt").exists());
+
+        let missing = tmp.path().join("missing");
+        let result = run(&args("resources/test.tar", &missing), false);
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn unarchive_escape() {
+        let member = |name: &str, kind| Member::new(name.to_string(), kind);
+        let symlink = |target: &str| Kind::Symlink(target.to_string());
+        let escape = |member: &Member| escape(member, &HashMap::new());
+        assert_eq!(escape(&member("docs/a.txt", Kind::File)), None);
+        assert_eq!(escape(&member("./docs/", Kind::Dir)), None);
+        assert_eq!(escape(&member("docs/link", symlink("../a.txt"))), None);
+        assert_eq!(escape(&member("link", symlink("docs/./a.txt"))), None);
+        let hardlink =
+            member("docs/b.txt", Kind::Hardlink("docs/a.txt".into()));
+        assert_eq!(escape(&hardlink), None);
+
+        assert!(escape(&member("/etc/passwd", Kind::File)).is_some());
+        assert!(escape(&member("docs/../a.txt", Kind::File)).is_some());
+        assert!(escape(&member("docs/link", symlink("../../etc"))).is_some());
+        assert!(escape(&member("link", symlink("/etc"))).is_some());
+        let hardlink = member("passwd", Kind::Hardlink("../passwd".into()));
+        assert!(escape(&hardlink).is_some());
+    }
+
+    #[test]
+    fn unarchive_escape_chains() {
+        let link = |name: &str, target: &str| {
+            Member::new(name.to_string(), Kind::Symlink(target.to_string()))
+        };
+        let escaping = |members: &[Member]| -> Vec<String> {
+            let links = symlinks(members);
+            members
+                .iter()
+                .filter(|member| escape(member, &links).is_some())
+                .map(|member| member.name.clone())
+                .collect()
+        };
+        let members =
+            [link("current", "releases/2"), link("bin", "./current/bin")];
+        assert!(escaping(&members).is_empty());
+
+        // Each link stays inside by its text alone
+        let members = [link("sub", "."), link("sub/up", "..")];
+        assert_eq!(escaping(&members), ["sub/up"]);
+        let members = [link("sub", "."), link("up", "sub/..")];
+        assert_eq!(escaping(&members), ["up"]);
+        let members = [link("a", "b/c"), link("b", "."), link("c", "..")];
+        assert_eq!(escaping(&members), ["a", "c"]);
+        let members = [
+            link("sub", "."),
+            Member::new("out".to_string(), Kind::Hardlink("sub/..".into())),
+        ];
+        assert_eq!(escaping(&members), ["out"]);
+        let members = [link("a", "b"), link("b", "a")];
+        assert_eq!(escaping(&members), ["a", "b"]);
+    }
+
+    #[test]
+    fn unarchive_unsafe() {
+        for (fixture, entries) in [
+            ("unsafe-absolute.tar", &["/tmp/rustible-unsafe.txt"][..]),
+            ("unsafe-parent.tar", &["docs/../../rustible-unsafe.txt"]),
+            ("unsafe-symlink.tar", &["etc -> /etc", "docs/up -> ../.."]),
+            (
+                "unsafe-hardlink.tar",
+                &["passwd -> /etc/passwd", "shadow -> ../shadow"],
+            ),
+            ("unsafe-absolute.zip", &["/tmp/rustible-unsafe.txt"]),
+            ("unsafe-parent.zip", &["../rustible-unsafe.txt"]),
+            ("unsafe-symlink.zip", &["etc -> ../../etc"]),
+        ] {
+            let tmp = tempfile::tempdir().unwrap();
+            let dest = tmp.path().join("dest");
+            fs::create_dir(&dest).unwrap();
+            let src = format!("resources/{fixture}");
+            for check_mode in [true, false] {
+                let error = run(&args(&src, &dest), check_mode).unwrap_err();
+                let error = error.to_string();
+                for entry in entries {
+                    assert!(error.contains(entry), "{fixture}: {error}");
+                }
+                assert!(!error.contains("test.txt"), "{fixture}: {error}");
+            }
+            // Nothing is extracted, not even the harmless members
+            assert_eq!(fs::read_dir(tmp.path()).unwrap().count(), 1);
+            assert_eq!(fs::read_dir(&dest).unwrap().count(), 0);
+        }
+    }
+
+    // A tarball of `members`, as (name, symlink target) or (name, contents)
+    fn build_tar(src: &Path, members: &[(&str, Option<&str>, &str)]) {
+        let mut builder = tar::Builder::new(fs::File::create(src).unwrap());
+        for (name, target, contents) in members {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            match target {
+                Some(target) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_size(0);
+                    builder.append_link(&mut header, name, target).unwrap();
+                }
+                None => {
+                    header.set_size(contents.len() as u64);
+                    builder
+                        .append_data(&mut header, name, contents.as_bytes())
+                        .unwrap();
+                }
+            }
+        }
+        builder.into_inner().unwrap();
+    }
+
+    #[test]
+    fn unarchive_symlink_chain() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("dest");
+        fs::create_dir(&dest).unwrap();
+        let members = [("sub", Some("."), ""), ("sub/up", Some(".."), "")];
+
+        let tar = tmp.path().join("chain.tar");
+        build_tar(&tar, &members);
+        let zip = tmp.path().join("chain.zip");
+        let mut writer = zip::ZipWriter::new(fs::File::create(&zip).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        for (name, target, _) in members {
+            writer.add_symlink(name, target.unwrap(), options).unwrap();
+        }
+        writer.finish().unwrap();
+
+        for src in [tar, zip] {
+            let src = src.to_string_lossy();
+            let error = run(&args(&src, &dest), false).unwrap_err().to_string();
+            assert!(error.contains("sub/up -> .."), "{error}");
+            assert!(!error.contains("sub -> ."), "{error}");
+            assert_eq!(fs::read_dir(&dest).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn unarchive_failure_removes_extracted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("dest");
+        fs::create_dir_all(dest.join("blocker")).unwrap();
+        fs::write(dest.join("blocker/kept.txt"), "kept").unwrap();
+        let src = tmp.path().join("blocked.tar");
+        build_tar(
+            &src,
+            &[
+                ("new/a.txt", None, "text"),
+                ("b.txt", None, "text"),
+                ("blocker", None, "a file where dest has a directory"),
+            ],
+        );
+
+        let src = src.to_string_lossy();
+        assert!(run(&args(&src, &dest), false).is_err());
+        assert!(!dest.join("new").exists());
+        assert!(!dest.join("b.txt").exists());
+        assert!(dest.join("blocker/kept.txt").exists());
+    }
+
+    #[test]
+    fn unarchive_args() {
+        let load = |source: &str| {
+            yaml_rust::YamlLoader::load_from_str(source)
+                .unwrap()
+                .remove(0)
+        };
+        let parsed = Unarchive
+            .parse_args(&load(
+                "src: app.tar.gz\ndest: /opt/app\ncreates: /opt/app/bin",
+            ))
+            .unwrap();
+        assert_eq!(parsed.src, "app.tar.gz");
+        assert_eq!(parsed.creates, Some(PathBuf::from("/opt/app/bin")));
+        assert!(!parsed.remote_src);
+        assert!(Unarchive.parse_args(&load("src: app.tar.gz")).is_err());
+        let url = "src: https://example.com/app.tar.gz\ndest: /opt/app";
+        assert!(Unarchive.parse_args(&load(url)).is_err());
+        let remote = format!("{url}\nremote_src: true");
+        assert!(Unarchive.parse_args(&load(&remote)).unwrap().remote_src);
+    }
+}
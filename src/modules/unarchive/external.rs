@@ -0,0 +1,245 @@
+// Reads the archive formats there's no crate for with their own tools
+use super::{spawn_error, Kind, Member};
+use crate::modules::archive::ArchiveType;
+use crate::modules::{ModuleError, Result};
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn execute(
+    mut command: Command,
+    archive_type: &ArchiveType,
+    src: &Path,
+) -> Result<Output> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .output()
+        .map_err(|e| spawn_error(&program, archive_type, e))?;
+    if !output.status.success() {
+        return Err(ModuleError::PlainMessage(format!(
+            "{program} failed on {}: {}",
+            src.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )));
+    }
+    Ok(output)
+}
+
+// The fields of one member in a technical listing, `Key = value` from 7z
+// and `Key: value` from unrar, with members separated by blank lines
+fn blocks<'a>(
+    stdout: &'a str,
+    separator: &str,
+) -> Vec<Vec<(&'a str, &'a str)>> {
+    let mut blocks = vec![vec![]];
+    for line in stdout.lines() {
+        match line.trim_start().split_once(separator) {
+            Some((key, value)) => {
+                blocks.last_mut().unwrap().push((key.trim(), value))
+            }
+            None if line.trim().is_empty() => blocks.push(vec![]),
+            None => {}
+        }
+    }
+    blocks.retain(|block| !block.is_empty());
+    blocks
+}
+
+// A unix mode, as in `-rw-r--r--`, among the words of an attributes field
+fn unix_mode(attributes: &str) -> Option<&str> {
+    attributes.split_whitespace().find(|word| {
+        word.len() == 10
+            && word.as_bytes()[1..].iter().all(|c| b"rwxsStT-".contains(c))
+    })
+}
+
+// The members in the output of a listing command. Links whose targets the
+// listing doesn't give are returned as the error, as they can't be checked.
+fn parse_listing(
+    archive_type: &ArchiveType,
+    stdout: &str,
+) -> std::result::Result<Vec<Member>, Vec<String>> {
+    let (name_key, separator) = match archive_type {
+        ArchiveType::Rar => ("Name", ": "),
+        _ => ("Path", " = "),
+    };
+    let mut members = vec![];
+    let mut unknown = vec![];
+    for block in blocks(stdout, separator) {
+        let field = |key: &str| {
+            block
+                .iter()
+                .find(|(field, _)| *field == key)
+                .map(|(_, value)| *value)
+        };
+        // Other blocks describe the archive itself
+        let Some(name) = field(name_key) else {
+            continue;
+        };
+        let value = |key: &str| field(key).filter(|value| !value.is_empty());
+        let target = value("Target")
+            .or_else(|| value("Symbolic Link"))
+            .or_else(|| value("Hard Link"));
+        let link = |hardlink: bool| match target {
+            Some(target) if hardlink => Ok(Kind::Hardlink(target.to_string())),
+            Some(target) => Ok(Kind::Symlink(target.to_string())),
+            None => Err(name.to_string()),
+        };
+        let symlink_mode = field("Attributes")
+            .and_then(unix_mode)
+            .is_some_and(|mode| mode.starts_with('l'));
+        let rar_type =
+            field("Type").filter(|_| *archive_type == ArchiveType::Rar);
+        let kind = match rar_type {
+            Some("Directory") => Ok(Kind::Dir),
+            Some("File") if !symlink_mode => Ok(Kind::File),
+            // Both copy another member
+            Some("Hard link" | "File reference") => link(true),
+            // Symbolic links and junction points
+            Some(_) => link(false),
+            None if field("Folder") == Some("+") => Ok(Kind::Dir),
+            None if value("Hard Link").is_some() => link(true),
+            None if symlink_mode || value("Symbolic Link").is_some() => {
+                link(false)
+            }
+            None => Ok(Kind::File),
+        };
+        match kind {
+            Ok(kind) => {
+                let mut member = Member::new(name.to_string(), kind);
+                if member.kind == Kind::File {
+                    member.size =
+                        field("Size").and_then(|size| size.parse().ok());
+                }
+                members.push(member);
+            }
+            Err(name) => unknown.push(name),
+        }
+    }
+    match unknown.is_empty() {
+        true => Ok(members),
+        false => Err(unknown),
+    }
+}
+
+/// Lists the members of a rar or 7z archive
+pub fn members(archive_type: &ArchiveType, src: &Path) -> Result<Vec<Member>> {
+    let mut list;
+    match archive_type {
+        ArchiveType::Rar => {
+            list = Command::new("unrar");
+            list.arg("lt").arg(src);
+        }
+        _ => {
+            list = Command::new("7z");
+            list.args(["l", "-ba", "-slt"]).arg(src);
+        }
+    }
+    let listing = execute(list, archive_type, src)?;
+    parse_listing(archive_type, &String::from_utf8_lossy(&listing.stdout))
+        .map_err(|links| {
+            ModuleError::PlainMessage(format!(
+                "Refusing to extract {}, the targets of these links can't be \
+             checked: {}",
+                src.display(),
+                links.join(", ")
+            ))
+        })
+}
+
+/// Extracts a rar or 7z archive into `dest`
+pub fn extract(
+    archive_type: &ArchiveType,
+    src: &Path,
+    dest: &Path,
+) -> Result<()> {
+    let mut extract;
+    match archive_type {
+        ArchiveType::Rar => {
+            extract = Command::new("unrar");
+            // unrar only takes the destination as a directory with a
+            // trailing separator
+            extract
+                .args(["x", "-o+", "-idq"])
+                .arg(src)
+                .arg(dest.join(""));
+        }
+        _ => {
+            extract = Command::new("7z");
+            extract
+                .args(["x", "-y", "-bd"])
+                .arg(format!("-o{}", dest.display()))
+                .arg(src);
+        }
+    }
+    execute(extract, archive_type, src)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::check_members;
+    use super::*;
+
+    #[test]
+    fn external_parse_listing() {
+        let names = |members: &[Member]| -> Vec<String> {
+            members.iter().map(|member| member.name.clone()).collect()
+        };
+        let listing = "Path = docs\nSize = 0\nFolder = +\n\n\
+                       Path = docs/a.txt\nSize = 20\nFolder = -\n";
+        let members = parse_listing(&ArchiveType::SevenZip, listing).unwrap();
+        assert_eq!(names(&members), ["docs", "docs/a.txt"]);
+        assert_eq!(members[0].kind, Kind::Dir);
+        assert_eq!(members[0].size, None);
+        assert_eq!(members[1].kind, Kind::File);
+        assert_eq!(members[1].size, Some(20));
+
+        let listing = "\nArchive: docs.rar\nDetails: RAR 5\n\n\
+                       \x20       Name: docs\n        Type: Directory\n\n\
+                       \x20       Name: docs/a.txt\n        Type: File\n\
+                       \x20       Size: 20\n  Attributes: -rw-r--r--\n";
+        let members = parse_listing(&ArchiveType::Rar, listing).unwrap();
+        assert_eq!(names(&members), ["docs", "docs/a.txt"]);
+        assert_eq!(members[0].kind, Kind::Dir);
+        assert_eq!(members[1].size, Some(20));
+    }
+
+    #[test]
+    fn external_parse_links() {
+        let listing = "Path = link\nSize = 4\nFolder = -\n\
+                       Attributes = A_ lrwxrwxrwx\nSymbolic Link = /etc\n\n\
+                       Path = link/x\nSize = 1\nFolder = -\n\
+                       Attributes = A_ -rw-r--r--\n";
+        let members = parse_listing(&ArchiveType::SevenZip, listing).unwrap();
+        assert_eq!(members[0].kind, Kind::Symlink("/etc".to_string()));
+        assert_eq!(members[1].kind, Kind::File);
+        let error = check_members("links.7z", Path::new("/srv"), &members);
+        let error = error.unwrap_err().to_string();
+        assert!(
+            error.contains("link -> /etc") && error.contains("link/x"),
+            "{error}"
+        );
+
+        // 7z archives keep a link's target as its contents
+        let listing = "Path = link\nSize = 4\nFolder = -\n\
+                       Attributes = A_ lrwxrwxrwx\n";
+        let unknown =
+            parse_listing(&ArchiveType::SevenZip, listing).unwrap_err();
+        assert_eq!(unknown, ["link"]);
+
+        let listing = "        Name: link\n        Type: Unix symbolic link\n\
+                       \x20     Target: /etc\n\n\
+                       \x20       Name: copy\n        Type: Hard link\n\
+                       \x20     Target: docs/a.txt\n\n\
+                       \x20       Name: old\n        Type: Unix symbolic link\n";
+        assert_eq!(
+            parse_listing(&ArchiveType::Rar, listing).unwrap_err(),
+            ["old"]
+        );
+        let listing = listing.rsplit_once("\n\n").unwrap().0;
+        let members = parse_listing(&ArchiveType::Rar, listing).unwrap();
+        assert_eq!(members[0].kind, Kind::Symlink("/etc".to_string()));
+        assert_eq!(members[1].kind, Kind::Hardlink("docs/a.txt".to_string()));
+    }
+}
@@ -0,0 +1,296 @@
+// Reads tar and zip archives in-process, so the common formats can be
+// extracted on hosts without `tar` or `unzip`. Tarballs compressed with
+// something there's no crate for are decompressed by an external program
+// and still unpacked here.
+use super::{spawn_error, Kind, Member};
+use crate::modules::archive::ArchiveType;
+use crate::modules::{ModuleError, Result};
+
+use std::fs::{self, File, Permissions};
+use std::io::{self, BufReader, Read};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Child, ChildStdout, Command, Stdio};
+use std::time::{Duration, UNIX_EPOCH};
+use tar::EntryType;
+
+// The program decompressing a tarball to stdout, for formats without a
+// native decoder
+fn decompressor(archive_type: &ArchiveType) -> Option<&'static str> {
+    match archive_type {
+        ArchiveType::TarLzip => Some("lzip"),
+        ArchiveType::TarLzop => Some("lzop"),
+        ArchiveType::TarCompress => Some("uncompress"),
+        _ => None,
+    }
+}
+
+// The output of a decompressor, which fails at the end of the stream when
+// the program did
+struct Decompressor {
+    program: &'static str,
+    child: Child,
+    stdout: ChildStdout,
+}
+
+impl Decompressor {
+    fn spawn(
+        program: &'static str,
+        archive_type: &ArchiveType,
+        src: &Path,
+    ) -> Result<Self> {
+        let mut child = Command::new(program)
+            .args(["-d", "-c"])
+            .arg(src)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| spawn_error(program, archive_type, e))?;
+        let stdout = child.stdout.take().expect("stdout is piped");
+        Ok(Self {
+            program,
+            child,
+            stdout,
+        })
+    }
+}
+
+impl Read for Decompressor {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.stdout.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            let mut stderr = String::new();
+            if let Some(mut pipe) = self.child.stderr.take() {
+                pipe.read_to_string(&mut stderr)?;
+            }
+            if !self.child.wait()?.success() {
+                return Err(io::Error::other(format!(
+                    "{} failed: {}",
+                    self.program,
+                    stderr.trim()
+                )));
+            }
+        }
+        Ok(n)
+    }
+}
+
+impl Drop for Decompressor {
+    fn drop(&mut self) {
+        // Only still running when the archive wasn't read to the end
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// The uncompressed tar stream of a tarball
+fn tar_stream(archive_type: &ArchiveType, src: &Path) -> Result<Box<dyn Read>> {
+    if let Some(program) = decompressor(archive_type) {
+        return Ok(Box::new(Decompressor::spawn(program, archive_type, src)?));
+    }
+    let file = BufReader::new(File::open(src)?);
+    Ok(match archive_type {
+        ArchiveType::TarGzip => {
+            Box::new(flate2::read::MultiGzDecoder::new(file))
+        }
+        ArchiveType::TarBzip2 => {
+            Box::new(bzip2::read::MultiBzDecoder::new(file))
+        }
+        ArchiveType::TarXz => {
+            Box::new(xz2::read::XzDecoder::new_multi_decoder(file))
+        }
+        ArchiveType::TarZstd => Box::new(zstd::Decoder::with_buffer(file)?),
+        _ => Box::new(file),
+    })
+}
+
+fn read_error(src: &Path, e: impl std::fmt::Display) -> ModuleError {
+    ModuleError::PlainMessage(format!("Failed to read {}: {e}", src.display()))
+}
+
+fn extract_error(src: &Path, e: impl std::fmt::Display) -> ModuleError {
+    ModuleError::PlainMessage(format!(
+        "Failed to extract {}: {e}",
+        src.display()
+    ))
+}
+
+fn tar_members(archive_type: &ArchiveType, src: &Path) -> Result<Vec<Member>> {
+    let mut archive = tar::Archive::new(tar_stream(archive_type, src)?);
+    let mut members = vec![];
+    for entry in archive.entries().map_err(|e| read_error(src, e))? {
+        let entry = entry.map_err(|e| read_error(src, e))?;
+        let link = || {
+            let target = entry.link_name_bytes().unwrap_or_default();
+            String::from_utf8_lossy(&target).into_owned()
+        };
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            EntryType::Directory => Kind::Dir,
+            EntryType::Symlink => Kind::Symlink(link()),
+            EntryType::Link => Kind::Hardlink(link()),
+            _ => Kind::File,
+        };
+        let name = String::from_utf8_lossy(&entry.path_bytes()).into_owned();
+        let mut member = Member::new(name, kind);
+        member.mode = header.mode().ok();
+        if member.kind == Kind::File {
+            member.size = Some(entry.size());
+            member.mtime = header.mtime().ok();
+        }
+        members.push(member);
+    }
+    Ok(members)
+}
+
+// Seconds since the epoch of a zip member's time. Zip files don't say which
+// timezone their times are in, they're taken to be UTC.
+fn dos_time(time: zip::DateTime) -> u64 {
+    // Days from the civil date, after Howard Hinnant's algorithm
+    let (month, day) = (u64::from(time.month()), u64::from(time.day()));
+    let year = u64::from(time.year()) - u64::from(month <= 2);
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era =
+        year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146_097 + day_of_era - 719_468;
+    let seconds = u64::from(time.hour()) * 3600
+        + u64::from(time.minute()) * 60
+        + u64::from(time.second());
+    days * 86_400 + seconds
+}
+
+fn zip_members(src: &Path) -> Result<Vec<Member>> {
+    let file = BufReader::new(File::open(src)?);
+    let mut archive =
+        zip::ZipArchive::new(file).map_err(|e| read_error(src, e))?;
+    let mut members = vec![];
+    for index in 0..archive.len() {
+        let mut member =
+            archive.by_index(index).map_err(|e| read_error(src, e))?;
+        // A symlink's target is stored as its contents
+        let kind = if member.is_symlink() {
+            let mut target = String::new();
+            member.read_to_string(&mut target)?;
+            Kind::Symlink(target)
+        } else if member.is_dir() {
+            Kind::Dir
+        } else {
+            Kind::File
+        };
+        let mut listed = Member::new(member.name().to_string(), kind);
+        listed.mode = member.unix_mode();
+        if listed.kind == Kind::File {
+            listed.size = Some(member.size());
+            listed.mtime = member.last_modified().map(dos_time);
+        }
+        members.push(listed);
+    }
+    Ok(members)
+}
+
+/// Lists the members of a tarball or zip file
+pub fn members(archive_type: &ArchiveType, src: &Path) -> Result<Vec<Member>> {
+    match archive_type {
+        ArchiveType::Zip => zip_members(src),
+        _ => tar_members(archive_type, src),
+    }
+}
+
+/// Extracts a tarball or zip file into `dest`
+pub fn extract(
+    archive_type: &ArchiveType,
+    src: &Path,
+    dest: &Path,
+    members: &[Member],
+) -> Result<()> {
+    if let ArchiveType::Zip = archive_type {
+        let file = BufReader::new(File::open(src)?);
+        let mut archive =
+            zip::ZipArchive::new(file).map_err(|e| read_error(src, e))?;
+        archive.extract(dest).map_err(|e| extract_error(src, e))?;
+        // Unlike tar, the zip crate leaves files with the time of extraction
+        // and directories with the default mode
+        for member in members {
+            if let Some(mtime) = member.mtime {
+                let mtime = UNIX_EPOCH + Duration::from_secs(mtime);
+                File::open(dest.join(&member.name))?.set_modified(mtime)?;
+            }
+        }
+        // Children first, so that a directory's mode can't lock them out
+        for member in members.iter().rev() {
+            if let (Kind::Dir, Some(mode)) = (&member.kind, member.mode) {
+                let permissions = Permissions::from_mode(mode & 0o777);
+                fs::set_permissions(dest.join(&member.name), permissions)?;
+            }
+        }
+        return Ok(());
+    }
+    let mut archive = tar::Archive::new(tar_stream(archive_type, src)?);
+    archive.unpack(dest).map_err(|e| extract_error(src, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn native_extract() {
+        let expected = fs::read_to_string("resources/test.txt").unwrap();
+        for (ext, archive_type) in [
+            ("tar", ArchiveType::Tar),
+            ("tar.gz", ArchiveType::TarGzip),
+            ("tar.bz2", ArchiveType::TarBzip2),
+            ("tar.xz", ArchiveType::TarXz),
+            ("tar.zst", ArchiveType::TarZstd),
+            ("zip", ArchiveType::Zip),
+        ] {
+            let src = format!("resources/test.{ext}");
+            let tmp = tempfile::tempdir().unwrap();
+            let listed = members(&archive_type, src.as_ref()).unwrap();
+            assert_eq!(listed.len(), 1, "{ext}");
+            assert_eq!(listed[0].name, "test.txt", "{ext}");
+            assert_eq!(listed[0].size, Some(20), "{ext}");
+            // 2024-07-13 15:25, in whichever timezone the archiver was in
+            let mtime = listed[0].mtime.unwrap();
+            assert!(mtime.abs_diff(1_720_884_300) < 14 * 3600, "{ext}");
+
+            extract(&archive_type, src.as_ref(), tmp.path(), &listed).unwrap();
+            let extracted = fs::read_to_string(tmp.path().join("test.txt"));
+            assert_eq!(extracted.unwrap(), expected, "{ext}");
+        }
+    }
+
+    #[test]
+    fn native_dos_time() {
+        let time = zip::DateTime::from_date_and_time(2024, 7, 13, 15, 25, 0);
+        assert_eq!(dos_time(time.unwrap()), 1_720_884_300);
+        let time = zip::DateTime::from_date_and_time(1980, 1, 1, 0, 0, 0);
+        assert_eq!(dos_time(time.unwrap()), 315_532_800);
+        let time = zip::DateTime::from_date_and_time(2000, 2, 29, 23, 59, 58);
+        assert_eq!(dos_time(time.unwrap()), 951_868_798);
+    }
+
+    #[test]
+    fn native_decompressor() {
+        // `uncompress` ships with gzip, unlike lzop
+        let src = Path::new("resources/test.tar.Z");
+        let listed = members(&ArchiveType::TarCompress, src).unwrap();
+        assert_eq!(listed[0].name, "test.txt");
+
+        let tmp = tempfile::tempdir().unwrap();
+        let corrupt = tmp.path().join("corrupt.tar.Z");
+        fs::write(&corrupt, b"\x1f\x9dnot compressed").unwrap();
+        let result = members(&ArchiveType::TarCompress, &corrupt);
+        assert!(result.is_err());
+
+        let missing = spawn_error(
+            "lzop",
+            &ArchiveType::TarLzop,
+            io::Error::from(io::ErrorKind::NotFound),
+        );
+        assert!(missing.to_string().contains("`lzop`"), "{missing}");
+    }
+}
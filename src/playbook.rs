@@ -1,20 +1,545 @@
+// Loads playbooks from YAML into plays and tasks.
+//
+// `yaml-rust`'s own `YamlLoader` throws away source positions, so the
+// `PlayParser` builds its own tree from the parser events to keep the
+// line/column of every node around for error reporting.
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+
+use yaml_rust::parser::{Event, MarkedEventReceiver, Parser};
+use yaml_rust::scanner::{Marker, ScanError, TScalarStyle, TokenType};
+use yaml_rust::yaml::Hash;
+use yaml_rust::Yaml;
+
+pub type Result<T> = std::result::Result<T, PlaybookError>;
+
+/// A position in the playbook source, both 1-based
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Mark {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl From<Marker> for Mark {
+    fn from(marker: Marker) -> Self {
+        Mark {
+            line: marker.line(),
+            col: marker.col() + 1,
+        }
+    }
+}
+
+impl fmt::Display for Mark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.col)
+    }
+}
+
+#[derive(Debug)]
+pub enum PlaybookError {
+    IOError(std::io::Error),
+    ScanError(ScanError),
+    Invalid(String, Mark),
+}
+
+impl PlaybookError {
+    fn invalid(msg: impl Into<String>, mark: Mark) -> Self {
+        Self::Invalid(msg.into(), mark)
+    }
+}
+
+impl fmt::Display for PlaybookError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IOError(e) => write!(f, "{e}"),
+            Self::ScanError(e) => write!(f, "Syntax error: {e}"),
+            Self::Invalid(msg, mark) => write!(f, "{msg} (at {mark})"),
+        }
+    }
+}
+
+impl std::error::Error for PlaybookError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Self::IOError(ref err) => Some(err),
+            Self::ScanError(ref err) => Some(err),
+            Self::Invalid(..) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for PlaybookError {
+    fn from(value: std::io::Error) -> Self {
+        PlaybookError::IOError(value)
+    }
+}
+
+impl From<ScanError> for PlaybookError {
+    fn from(value: ScanError) -> Self {
+        PlaybookError::ScanError(value)
+    }
+}
+
+#[derive(Debug)]
 pub struct Playbook {
-    plays: Vec<Play>,
+    pub plays: Vec<Play>,
+}
+
+impl Playbook {
+    pub fn new(plays: Vec<Play>) -> Self {
+        Self { plays }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let source = std::fs::read_to_string(path)?;
+        PlayParser::new().parse(&source)
+    }
+}
+
+#[derive(Debug)]
+pub struct Play {
+    pub name: String,
+    pub hosts: Vec<String>,
+    pub remote_user: Option<String>,
+    pub tasks: Vec<Task>,
+    pub mark: Mark,
+}
+
+#[derive(Debug)]
+pub struct Task {
+    pub name: Option<String>,
+    /// The module name exactly as written, e.g. `rustible.builtin.git`
+    pub module: String,
+    /// Always a `Yaml::Hash`, empty when the module was given no arguments
+    pub args: Yaml,
+    /// Variable to store the task's result in
+    pub register: Option<String>,
+    pub mark: Mark,
 }
 
-impl struct Playbook {
-    pub fn new(plays: Vec<Play>) -> Self { Self {plays}}
+impl Task {
+    /// The name to display for this task, falling back to the module
+    pub fn display_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.module)
+    }
+}
+
+// A YAML node which remembers where it started in the source
+#[derive(Debug, Clone)]
+struct Node {
+    value: NodeValue,
+    mark: Mark,
+}
+
+#[derive(Debug, Clone)]
+enum NodeValue {
+    Scalar(Yaml),
+    Sequence(Vec<Node>),
+    Mapping(Vec<(Node, Node)>),
+}
 
+impl Node {
+    fn to_yaml(&self) -> Yaml {
+        match &self.value {
+            NodeValue::Scalar(yaml) => yaml.clone(),
+            NodeValue::Sequence(items) => {
+                Yaml::Array(items.iter().map(Node::to_yaml).collect())
+            }
+            NodeValue::Mapping(entries) => Yaml::Hash(
+                entries
+                    .iter()
+                    .map(|(k, v)| (k.to_yaml(), v.to_yaml()))
+                    .collect::<Hash>(),
+            ),
+        }
+    }
 
+    fn as_str(&self) -> Option<&str> {
+        match &self.value {
+            NodeValue::Scalar(Yaml::String(s)) => Some(s),
+            _ => None,
+        }
+    }
 
-    // pub fn load(parser: PlayParser) -> Self {
-    //     plays = parser.par
-    //
-    // }
+    // Numbers and booleans are accepted where a string is expected,
+    // the same way `version: 1.0` should still mean "1.0"
+    fn to_string_lossy(&self) -> Option<String> {
+        match &self.value {
+            NodeValue::Scalar(Yaml::String(s)) => Some(s.clone()),
+            NodeValue::Scalar(Yaml::Integer(i)) => Some(i.to_string()),
+            NodeValue::Scalar(Yaml::Real(r)) => Some(r.clone()),
+            NodeValue::Scalar(Yaml::Boolean(b)) => Some(b.to_string()),
+            _ => None,
+        }
+    }
+
+    fn is_null(&self) -> bool {
+        matches!(self.value, NodeValue::Scalar(Yaml::Null))
+    }
 }
 
+/// Parses playbook source into a `Playbook`.
+///
+/// Implements `MarkedEventReceiver` to build a tree of marked nodes, which is
+/// then validated and converted into plays and tasks.
+#[derive(Default)]
 pub struct PlayParser {
+    docs: Vec<Node>,
+    // (node being built, anchor id)
+    stack: Vec<(Node, usize)>,
+    keys: Vec<Option<Node>>,
+    anchors: BTreeMap<usize, Node>,
+}
+
+impl MarkedEventReceiver for PlayParser {
+    fn on_event(&mut self, ev: Event, marker: Marker) {
+        let mark = Mark::from(marker);
+        match ev {
+            Event::DocumentEnd => match self.stack.pop() {
+                Some((node, _)) => self.docs.push(node),
+                None => self.docs.push(Node {
+                    value: NodeValue::Scalar(Yaml::Null),
+                    mark,
+                }),
+            },
+            Event::SequenceStart(aid) => {
+                let value = NodeValue::Sequence(Vec::new());
+                self.stack.push((Node { value, mark }, aid));
+            }
+            Event::MappingStart(aid) => {
+                let value = NodeValue::Mapping(Vec::new());
+                self.stack.push((Node { value, mark }, aid));
+                self.keys.push(None);
+            }
+            Event::SequenceEnd => {
+                let node = self.stack.pop().expect("unbalanced sequence");
+                self.insert(node);
+            }
+            Event::MappingEnd => {
+                self.keys.pop();
+                let (mut node, aid) =
+                    self.stack.pop().expect("unbalanced mapping");
+                // Block mappings are marked at their first ':', point at
+                // the first key instead
+                if let NodeValue::Mapping(entries) = &node.value {
+                    if let Some((key, _)) = entries.first() {
+                        node.mark = key.mark;
+                    }
+                }
+                self.insert((node, aid));
+            }
+            Event::Scalar(value, style, aid, tag) => {
+                let value =
+                    NodeValue::Scalar(scalar_to_yaml(value, style, tag));
+                self.insert((Node { value, mark }, aid));
+            }
+            Event::Alias(id) => {
+                let node = self.anchors.get(&id).cloned().unwrap_or(Node {
+                    value: NodeValue::Scalar(Yaml::BadValue),
+                    mark,
+                });
+                self.insert((node, 0));
+            }
+            _ => {}
+        }
+    }
+}
 
+fn scalar_to_yaml(
+    value: String,
+    style: TScalarStyle,
+    tag: Option<TokenType>,
+) -> Yaml {
+    if style != TScalarStyle::Plain {
+        return Yaml::String(value);
+    }
+    match tag {
+        Some(TokenType::Tag(handle, suffix))
+            if handle == "!!" && suffix == "str" =>
+        {
+            Yaml::String(value)
+        }
+        _ => Yaml::from_str(&value),
+    }
 }
 
-pub struct Play {}
+impl PlayParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&mut self, (node, aid): (Node, usize)) {
+        // valid anchor ids start from 1
+        if aid > 0 {
+            self.anchors.insert(aid, node.clone());
+        }
+
+        let Some((parent, _)) = self.stack.last_mut() else {
+            self.stack.push((node, aid));
+            return;
+        };
+
+        match &mut parent.value {
+            NodeValue::Sequence(items) => items.push(node),
+            NodeValue::Mapping(entries) => {
+                let key = self.keys.last_mut().expect("mapping without key");
+                match key.take() {
+                    Some(k) => entries.push((k, node)),
+                    None => *key = Some(node),
+                }
+            }
+            NodeValue::Scalar(_) => unreachable!("scalars have no children"),
+        }
+    }
+
+    /// Parses the source of a playbook: a single YAML document holding a
+    /// list of plays
+    pub fn parse(mut self, source: &str) -> Result<Playbook> {
+        let mut parser = Parser::new(source.chars());
+        parser.load(&mut self, false)?;
+
+        let Some(root) = self.docs.into_iter().next() else {
+            return Ok(Playbook::new(Vec::new()));
+        };
+
+        let NodeValue::Sequence(items) = root.value else {
+            return Err(PlaybookError::invalid(
+                "A playbook must be a list of plays",
+                root.mark,
+            ));
+        };
+
+        let plays = items.iter().map(parse_play).collect::<Result<_>>()?;
+        Ok(Playbook::new(plays))
+    }
+}
+
+fn mapping<'a>(node: &'a Node, what: &str) -> Result<&'a [(Node, Node)]> {
+    match &node.value {
+        NodeValue::Mapping(entries) => Ok(entries),
+        _ => Err(PlaybookError::invalid(
+            format!("A {what} must be a mapping"),
+            node.mark,
+        )),
+    }
+}
+
+fn string(node: &Node, key: &str) -> Result<String> {
+    node.to_string_lossy().ok_or_else(|| {
+        PlaybookError::invalid(format!("`{key}` must be a string"), node.mark)
+    })
+}
+
+fn parse_hosts(node: &Node) -> Result<Vec<String>> {
+    let hosts = match &node.value {
+        NodeValue::Sequence(items) => items
+            .iter()
+            .map(|item| string(item, "hosts"))
+            .collect::<Result<Vec<_>>>()?,
+        _ => string(node, "hosts")?
+            .split(',')
+            .map(|host| host.trim().to_string())
+            .collect(),
+    };
+
+    let hosts: Vec<String> =
+        hosts.into_iter().filter(|host| !host.is_empty()).collect();
+    if hosts.is_empty() {
+        return Err(PlaybookError::invalid("`hosts` is empty", node.mark));
+    }
+    Ok(hosts)
+}
+
+fn parse_play(node: &Node) -> Result<Play> {
+    let mut name = None;
+    let mut hosts = None;
+    let mut remote_user = None;
+    let mut tasks = Vec::new();
+
+    for (key, value) in mapping(node, "play")? {
+        let Some(k) = key.as_str() else {
+            return Err(PlaybookError::invalid(
+                "Play keys must be strings",
+                key.mark,
+            ));
+        };
+        match k {
+            "name" => name = Some(string(value, k)?),
+            "hosts" => hosts = Some(parse_hosts(value)?),
+            "remote_user" | "remote_usr" => {
+                remote_user = Some(string(value, k)?)
+            }
+            "tasks" => {
+                if value.is_null() {
+                    continue;
+                }
+                let NodeValue::Sequence(items) = &value.value else {
+                    return Err(PlaybookError::invalid(
+                        "`tasks` must be a list",
+                        value.mark,
+                    ));
+                };
+                tasks = items.iter().map(parse_task).collect::<Result<_>>()?;
+            }
+            _ => {
+                return Err(PlaybookError::invalid(
+                    format!("`{k}` is not a valid attribute for a play"),
+                    key.mark,
+                ))
+            }
+        }
+    }
+
+    let Some(hosts) = hosts else {
+        return Err(PlaybookError::invalid(
+            "A play requires `hosts`",
+            node.mark,
+        ));
+    };
+
+    Ok(Play {
+        name: name.unwrap_or_else(|| hosts.join(",")),
+        hosts,
+        remote_user,
+        tasks,
+        mark: node.mark,
+    })
+}
+
+fn parse_task(node: &Node) -> Result<Task> {
+    let mut name = None;
+    let mut register = None;
+    let mut action: Option<(&Node, &Node)> = None;
+
+    for (key, value) in mapping(node, "task")? {
+        let Some(k) = key.as_str() else {
+            return Err(PlaybookError::invalid(
+                "Task keys must be strings",
+                key.mark,
+            ));
+        };
+        match k {
+            "name" => name = Some(string(value, k)?),
+            "register" => register = Some(string(value, k)?),
+            _ => {
+                if let Some((previous, _)) = action {
+                    return Err(PlaybookError::invalid(
+                        format!(
+                            "Conflicting action statements: `{}` and `{k}`",
+                            previous.as_str().unwrap_or_default()
+                        ),
+                        key.mark,
+                    ));
+                }
+                action = Some((key, value));
+            }
+        }
+    }
+
+    let Some((module, args)) = action else {
+        return Err(PlaybookError::invalid(
+            "No module/action detected in task",
+            node.mark,
+        ));
+    };
+
+    let args = match &args.value {
+        NodeValue::Mapping(_) => args.to_yaml(),
+        NodeValue::Scalar(Yaml::Null) => Yaml::Hash(Hash::new()),
+        _ => {
+            return Err(PlaybookError::invalid(
+                format!(
+                    "Arguments to `{}` must be a mapping",
+                    module.as_str().unwrap_or_default()
+                ),
+                args.mark,
+            ))
+        }
+    };
+
+    Ok(Task {
+        name,
+        module: module.as_str().unwrap_or_default().to_string(),
+        args,
+        register,
+        mark: node.mark,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PLAYBOOK: &str = r#"
+- name: Download repo
+  hosts: localhost
+  remote_usr: root
+
+  tasks:
+  - name: Git checkout
+    rustible.builtin.git:
+      repo: "https://github.com/SickMcNugget/Waste_Detection_New.git"
+      dest: Waste_Detection_New
+      version: detr_setup
+  - git:
+    register: checkout
+"#;
+
+    #[test]
+    fn parse_playbook() {
+        let playbook = PlayParser::new().parse(PLAYBOOK).unwrap();
+        assert_eq!(playbook.plays.len(), 1);
+
+        let play = &playbook.plays[0];
+        assert_eq!(play.name, "Download repo");
+        assert_eq!(play.hosts, vec!["localhost"]);
+        assert_eq!(play.remote_user.as_deref(), Some("root"));
+        assert_eq!(play.mark, Mark { line: 2, col: 3 });
+        assert_eq!(play.tasks.len(), 2);
+
+        let task = &play.tasks[0];
+        assert_eq!(task.display_name(), "Git checkout");
+        assert_eq!(task.module, "rustible.builtin.git");
+        assert_eq!(task.args["dest"].as_str(), Some("Waste_Detection_New"));
+        assert_eq!(task.args["version"].as_str(), Some("detr_setup"));
+        assert_eq!(task.mark, Mark { line: 7, col: 5 });
+
+        let task = &play.tasks[1];
+        assert_eq!(task.display_name(), "git");
+        assert_eq!(task.args, Yaml::Hash(Hash::new()));
+        assert_eq!(task.register.as_deref(), Some("checkout"));
+    }
+
+    #[test]
+    fn parse_errors() {
+        const TESTTABLE: &[(&str, Mark)] = &[
+            ("name: not a list", Mark { line: 1, col: 1 }),
+            ("- name: no hosts", Mark { line: 1, col: 3 }),
+            ("- hosts: all\n  become: yes", Mark { line: 2, col: 3 }),
+            (
+                "- hosts: all\n  tasks:\n  - git: {}\n    apt: {}",
+                Mark { line: 4, col: 5 },
+            ),
+            (
+                "- hosts: all\n  tasks:\n  - name: nothing",
+                Mark { line: 3, col: 5 },
+            ),
+            (
+                "- hosts: all\n  tasks:\n  - git: [1, 2]",
+                Mark { line: 3, col: 10 },
+            ),
+        ];
+
+        for (source, expected) in TESTTABLE {
+            match PlayParser::new().parse(source) {
+                Err(PlaybookError::Invalid(_, mark)) => {
+                    assert_eq!(mark, *expected, "for {source:?}")
+                }
+                other => {
+                    panic!("expected an error for {source:?}, got {other:?}")
+                }
+            }
+        }
+    }
+}